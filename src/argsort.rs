@@ -0,0 +1,155 @@
+use super::context::Context;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::iter_sort::Codec;
+use super::iter_sort::StringCodec;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::time::Instant;
+
+/// What `argsort_by_key` writes for each line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Position {
+    /// 1-based line number in the input.
+    LineNumber,
+    /// Byte offset of the start of the line in the input.
+    Offset,
+}
+
+struct Entry {
+    line: String,
+    line_num: u64,
+    offset: u64,
+}
+
+struct EntryCodec;
+
+impl Codec<Entry> for EntryCodec {
+    fn encode(&self, entry: &Entry, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&entry.line_num.to_le_bytes());
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        StringCodec.encode(&entry.line, buf);
+    }
+
+    fn decode<R: BufRead>(&self, reader: &mut R) -> io::Result<Option<Entry>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut num = [0; 8];
+        reader.read_exact(&mut num)?;
+        let line_num = u64::from_le_bytes(num);
+        reader.read_exact(&mut num)?;
+        let offset = u64::from_le_bytes(num);
+
+        let line = StringCodec
+            .decode(reader)?
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+        Ok(Some(Entry {
+            line,
+            line_num,
+            offset,
+        }))
+    }
+}
+
+impl Sorter {
+    /// Write the position of each line of `fin` in sorted order, one per line,
+    /// instead of the lines themselves.
+    ///
+    /// Lines with equal keys are in input order; `stable(false)` does not apply.
+    /// The positions are sorted externally like `sort_iter_by_key_with`.
+    pub fn argsort_by_key<R, T, F, K>(
+        &self,
+        fin: R,
+        fout: T,
+        pos: Position,
+        key: F,
+    ) -> Result<SortStats>
+    where
+        R: io::Read,
+        T: io::Write,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let ctx = Context::new(self, 0);
+        let mut records = Records::new(io::BufReader::new(fin), self.delim, Source::Input);
+        let mut read_err = None;
+
+        let entries = std::iter::from_fn(|| {
+            let mut line = String::new();
+            let offset = records.offset();
+
+            match records.read(&mut line) {
+                Ok(0) => None,
+                Ok(_) => Some(Entry {
+                    line,
+                    line_num: records.line_num(),
+                    offset,
+                }),
+                Err(e) => {
+                    read_err = Some(e);
+                    None
+                }
+            }
+        });
+
+        let sorted = self.sort_iter_by_key_with(entries, EntryCodec, |e| key(&e.line))?;
+
+        if let Some(e) = read_err {
+            return Err(e);
+        }
+
+        // Runs and merges are counted by the external sort of the positions.
+        ctx.stats(|s| *s = sorted.stats().clone());
+        let start = Instant::now();
+        let mut writer = io::BufWriter::new(ctx.writer(fout));
+        let mut count = 0;
+
+        for entry in sorted {
+            let entry = entry?;
+
+            let n = match pos {
+                Position::LineNumber => entry.line_num,
+                Position::Offset => entry.offset,
+            };
+
+            writeln!(writer, "{}", n).map_err(Error::write)?;
+            count += 1;
+        }
+
+        writer.flush().map_err(Error::write)?;
+        drop(writer);
+
+        ctx.stats(|s| {
+            s.records = count;
+            s.output_duration += start.elapsed();
+        });
+
+        Ok(ctx.into_stats())
+    }
+}
+
+pub fn argsort_by_key<R, T, F, K>(
+    fin: R,
+    fout: T,
+    cap: u64,
+    pos: Position,
+    key: F,
+) -> Result<SortStats>
+where
+    R: io::Read,
+    T: io::Write,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .argsort_by_key(fin, fout, pos, key)
+}
@@ -0,0 +1,156 @@
+use super::CancelToken;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::io;
+use std::io::Seek;
+use std::time::Instant;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+use tokio::task;
+
+impl Sorter {
+    /// Same as `sort_by_key` for tokio readers and writers.
+    ///
+    /// The input is spooled to a temporary file, sorted on a blocking thread
+    /// and then copied to `fout`. Dropping the future cancels the blocking sort,
+    /// which then removes its temporary files.
+    pub async fn sort_by_key_async<R, T, F, K>(
+        &self,
+        mut fin: R,
+        mut fout: T,
+        key: F,
+    ) -> Result<SortStats>
+    where
+        R: AsyncRead + Unpin,
+        T: AsyncWrite + Unpin,
+        F: Fn(&String) -> K + Send + 'static,
+        K: Ord,
+    {
+        let token = match &self.cancel {
+            Some(token) => token.child(),
+            None => CancelToken::new(),
+        };
+
+        let _guard = CancelOnDrop(token.clone());
+        let sorter = self.clone().cancel_token(token);
+        let tmp = {
+            let sorter = sorter.clone();
+            blocking(move || sorter.tempfile().map_err(Error::TempSpace)).await?
+        };
+
+        let mut tmp = tokio::fs::File::from_std(tmp);
+        spool(&mut fin, &mut tmp).await?;
+        let mut tmp = tmp.into_std().await;
+
+        let (sorted, mut stats) = blocking(move || {
+            tmp.seek(io::SeekFrom::Start(0)).map_err(Error::TempSpace)?;
+            let mut out = sorter.tempfile().map_err(Error::TempSpace)?;
+            let stats = sorter.sort_by_key(tmp, &mut out, key)?;
+            out.seek(io::SeekFrom::Start(0)).map_err(Error::TempSpace)?;
+            Ok((out, stats))
+        })
+        .await?;
+
+        let start = Instant::now();
+        let mut sorted = tokio::fs::File::from_std(sorted);
+        copy_out(&mut sorted, &mut fout).await?;
+        stats.output_duration += start.elapsed();
+
+        Ok(stats)
+    }
+}
+
+pub async fn sort_by_key_async<R, T, F, K>(fin: R, fout: T, cap: u64, key: F) -> Result<SortStats>
+where
+    R: AsyncRead + Unpin,
+    T: AsyncWrite + Unpin,
+    F: Fn(&String) -> K + Send + 'static,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .sort_by_key_async(fin, fout, key)
+        .await
+}
+
+pub async fn reverse_sort_by_key_async<R, T, F, K>(
+    fin: R,
+    fout: T,
+    cap: u64,
+    key: F,
+) -> Result<SortStats>
+where
+    R: AsyncRead + Unpin,
+    T: AsyncWrite + Unpin,
+    F: Fn(&String) -> K + Send + 'static,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .reverse(true)
+        .sort_by_key_async(fin, fout, key)
+        .await
+}
+
+struct CancelOnDrop(CancelToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+// Copy `fin` to a temporary file, telling read failures from write failures.
+async fn spool<R>(fin: &mut R, tmp: &mut tokio::fs::File) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = fin.read(&mut buf).await.map_err(Error::Read)?;
+
+        if n == 0 {
+            break;
+        }
+
+        tmp.write_all(&buf[..n]).await.map_err(Error::TempSpace)?;
+    }
+
+    tmp.flush().await.map_err(Error::TempSpace)
+}
+
+async fn copy_out<T>(sorted: &mut tokio::fs::File, fout: &mut T) -> Result<()>
+where
+    T: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; 64 * 1024];
+
+    loop {
+        let n = sorted.read(&mut buf).await.map_err(Error::TempSpace)?;
+
+        if n == 0 {
+            break;
+        }
+
+        fout.write_all(&buf[..n]).await.map_err(Error::Write)?;
+    }
+
+    fout.flush().await.map_err(Error::Write)
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    match task::spawn_blocking(f).await {
+        Ok(res) => res,
+        Err(e) if e.is_panic() => std::panic::resume_unwind(e.into_panic()),
+        Err(_) => Err(Error::Cancelled),
+    }
+}
@@ -0,0 +1,303 @@
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::Range;
+
+// A key definition given by `-k POS1[,POS2]`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct KeyDef {
+    pub(crate) start_field: usize,
+    pub(crate) start_char: usize,
+    pub(crate) start_blanks: bool,
+    // `None` means the end of the line.
+    pub(crate) end_field: Option<usize>,
+    // 0 means the end of the field.
+    pub(crate) end_char: usize,
+    pub(crate) end_blanks: bool,
+    pub(crate) numeric: bool,
+    pub(crate) reverse: bool,
+    // The key has its own ordering options, so it does not inherit `-n`/`-r`.
+    pub(crate) has_opts: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct KeyDefError(String);
+
+impl fmt::Display for KeyDefError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid key definition: {}", self.0)
+    }
+}
+
+impl KeyDef {
+    pub(crate) fn whole_line() -> KeyDef {
+        KeyDef {
+            start_field: 1,
+            start_char: 1,
+            start_blanks: false,
+            end_field: None,
+            end_char: 0,
+            end_blanks: false,
+            numeric: false,
+            reverse: false,
+            has_opts: false,
+        }
+    }
+
+    pub(crate) fn parse(s: &str) -> Result<KeyDef, KeyDefError> {
+        let err = || KeyDefError(s.to_string());
+        let mut key = KeyDef::whole_line();
+        let mut parts = s.splitn(2, ',');
+
+        let (field, chr, opts) = parse_pos(parts.next().unwrap_or("")).ok_or_else(err)?;
+
+        if field == 0 || chr == Some(0) {
+            return Err(err());
+        }
+
+        key.start_field = field;
+        key.start_char = chr.unwrap_or(1);
+        key.start_blanks = opts.contains('b');
+        key.set_opts(opts).ok_or_else(err)?;
+
+        if let Some(end) = parts.next() {
+            let (field, chr, opts) = parse_pos(end).ok_or_else(err)?;
+
+            if field == 0 {
+                return Err(err());
+            }
+
+            key.end_field = Some(field);
+            key.end_char = chr.unwrap_or(0);
+            key.end_blanks = opts.contains('b');
+            key.set_opts(opts).ok_or_else(err)?;
+        }
+
+        Ok(key)
+    }
+
+    fn set_opts(&mut self, opts: &str) -> Option<()> {
+        for c in opts.chars() {
+            match c {
+                'b' => {}
+                'n' => self.numeric = true,
+                'r' => self.reverse = true,
+                _ => return None,
+            }
+
+            self.has_opts = true;
+        }
+
+        Some(())
+    }
+
+    pub(crate) fn extract(&self, line: &str, sep: Option<char>) -> Field {
+        let text = &line[self.range(line, sep)];
+
+        let value = if self.numeric {
+            Value::Num(Num::parse(text))
+        } else {
+            Value::Text(text.to_string())
+        };
+
+        Field {
+            value,
+            reverse: self.reverse,
+        }
+    }
+
+    pub(crate) fn range(&self, line: &str, sep: Option<char>) -> Range<usize> {
+        let fields = split_fields(line, sep);
+        let field_at = |n: usize| fields.get(n - 1).copied();
+
+        let start = match field_at(self.start_field) {
+            Some((s, e)) => {
+                let s = if self.start_blanks {
+                    skip_blanks(line, s, e)
+                } else {
+                    s
+                };
+                advance(line, s, e, self.start_char - 1)
+            }
+            None => line.len(),
+        };
+
+        let end = match self.end_field.map(field_at) {
+            Some(Some((s, e))) if self.end_char > 0 => {
+                let s = if self.end_blanks {
+                    skip_blanks(line, s, e)
+                } else {
+                    s
+                };
+                advance(line, s, e, self.end_char)
+            }
+            Some(Some((_, e))) => e,
+            _ => line.len(),
+        };
+
+        if start < end {
+            start..end
+        } else {
+            start..start
+        }
+    }
+}
+
+// Returns the field number, the character position and the options.
+fn parse_pos(s: &str) -> Option<(usize, Option<usize>, &str)> {
+    let opts_at = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (pos, opts) = s.split_at(opts_at);
+    let mut nums = pos.splitn(2, '.');
+    let field = nums.next()?.parse().ok()?;
+
+    let chr = match nums.next() {
+        Some(c) => Some(c.parse().ok()?),
+        None => None,
+    };
+
+    Some((field, chr, opts))
+}
+
+fn is_blank(c: char) -> bool {
+    c == ' ' || c == '\t'
+}
+
+// Byte ranges of the fields. Without a separator, a field is a run of
+// non-blank characters together with the blanks that precede it.
+fn split_fields(line: &str, sep: Option<char>) -> Vec<(usize, usize)> {
+    let mut fields = vec![];
+
+    if let Some(sep) = sep {
+        let mut start = 0;
+
+        for (i, c) in line.char_indices() {
+            if c == sep {
+                fields.push((start, i));
+                start = i + c.len_utf8();
+            }
+        }
+
+        fields.push((start, line.len()));
+        return fields;
+    }
+
+    let mut start = 0;
+
+    while start < line.len() {
+        let rest = &line[start..];
+        let nonblank = rest.find(|c| !is_blank(c)).unwrap_or(rest.len());
+        let len = rest[nonblank..]
+            .find(is_blank)
+            .map_or(rest.len(), |n| nonblank + n);
+        fields.push((start, start + len));
+        start += len;
+    }
+
+    fields
+}
+
+fn skip_blanks(line: &str, start: usize, end: usize) -> usize {
+    line[start..end]
+        .find(|c| !is_blank(c))
+        .map_or(end, |n| start + n)
+}
+
+fn advance(line: &str, start: usize, end: usize, chars: usize) -> usize {
+    line[start..end]
+        .char_indices()
+        .nth(chars)
+        .map_or(end, |(n, _)| start + n)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Field {
+    value: Value,
+    reverse: bool,
+}
+
+impl Ord for Field {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let ord = self.value.cmp(&other.value);
+
+        if self.reverse {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+}
+
+impl PartialOrd for Field {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Value {
+    Text(String),
+    Num(Num),
+}
+
+// A decimal number as read by `sort -n`. Text that is not a number is 0.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Num {
+    neg: bool,
+    // Without leading zeros.
+    int: String,
+    // Without trailing zeros.
+    frac: String,
+}
+
+impl Num {
+    pub(crate) fn parse(s: &str) -> Num {
+        let s = s.trim_start_matches(is_blank);
+        let (neg, s) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        let int_len = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+        let int = s[..int_len].trim_start_matches('0');
+        let mut frac = "";
+
+        if let Some(rest) = s[int_len..].strip_prefix('.') {
+            let frac_len = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            frac = rest[..frac_len].trim_end_matches('0');
+        }
+
+        Num {
+            neg: neg && !(int.is_empty() && frac.is_empty()),
+            int: int.to_string(),
+            frac: frac.to_string(),
+        }
+    }
+
+    fn cmp_abs(&self, other: &Self) -> Ordering {
+        self.int
+            .len()
+            .cmp(&other.int.len())
+            .then_with(|| self.int.cmp(&other.int))
+            .then_with(|| self.frac.cmp(&other.frac))
+    }
+}
+
+impl Ord for Num {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.neg, other.neg) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => self.cmp_abs(other),
+            (true, true) => other.cmp_abs(self),
+        }
+    }
+}
+
+impl PartialOrd for Num {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
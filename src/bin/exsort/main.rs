@@ -0,0 +1,328 @@
+//! `exsort` sorts lines of text files like sort(1), using ex_merge_sort_by_key.
+
+#[cfg(test)]
+mod tests;
+
+mod key;
+
+use ex_merge_sort_by_key::Sorter;
+use key::Field;
+use key::KeyDef;
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Seek;
+use std::io::Write;
+use std::process;
+
+const USAGE: &str = "Usage: exsort [OPTION]... [FILE]...";
+
+struct Options {
+    keys: Vec<KeyDef>,
+    sep: Option<char>,
+    sorter: Sorter,
+    tmp_dir: Option<String>,
+    zero: bool,
+    output: Option<String>,
+    merge: bool,
+    check: Option<bool>,
+    files: Vec<String>,
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    let opts = match parse_opts(&args) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => return,
+        Err(e) => {
+            eprintln!("exsort: {}\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    match run(&opts) {
+        Ok(true) => {}
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("exsort: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
+fn parse_opts(args: &[String]) -> Result<Option<Options>, String> {
+    let mut go = getopts::Options::new();
+    go.optmulti(
+        "k",
+        "key",
+        "sort via a key; KEYDEF is F[.C][OPTS][,F[.C][OPTS]]",
+        "KEYDEF",
+    );
+    go.optopt(
+        "t",
+        "field-separator",
+        "use SEP instead of non-blank to blank transition",
+        "SEP",
+    );
+    go.optflag(
+        "n",
+        "numeric-sort",
+        "compare according to string numerical value",
+    );
+    go.optflag("r", "reverse", "reverse the result of comparisons");
+    go.optflag("u", "unique", "output only the first of an equal run");
+    go.optflag("s", "stable", "disable last-resort comparison");
+    go.optopt(
+        "S",
+        "buffer-size",
+        "use SIZE for main memory buffer",
+        "SIZE",
+    );
+    go.optopt("T", "temporary-directory", "use DIR for temporaries", "DIR");
+    go.optopt(
+        "o",
+        "output",
+        "write result to FILE instead of standard output",
+        "FILE",
+    );
+    go.optflag("m", "merge", "merge already sorted files; do not sort");
+    go.optflag("c", "check", "check for sorted input; do not sort");
+    go.optflag("C", "", "like -c, but do not report first bad line");
+    go.optflag("z", "zero-terminated", "line delimiter is NUL, not newline");
+    go.optflag("h", "help", "display this help and exit");
+
+    let m = go.parse(args).map_err(|e| e.to_string())?;
+
+    if m.opt_present("h") {
+        print!("{}", go.usage(USAGE));
+        return Ok(None);
+    }
+
+    let sep = match m.opt_str("t") {
+        Some(t) => {
+            let mut chars = t.chars();
+
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => Some(c),
+                _ => return Err(format!("multi-character tab '{}'", t)),
+            }
+        }
+        None => None,
+    };
+
+    let numeric = m.opt_present("n");
+    let reverse = m.opt_present("r");
+    let mut keys = vec![];
+
+    for k in m.opt_strs("k") {
+        keys.push(KeyDef::parse(&k).map_err(|e| e.to_string())?);
+    }
+
+    if keys.is_empty() {
+        keys.push(KeyDef::whole_line());
+    }
+
+    for key in keys.iter_mut() {
+        if key.has_opts {
+            // `-r` reverses the whole order, so undo it for keys with their own options.
+            key.reverse ^= reverse;
+        } else {
+            key.numeric = numeric;
+        }
+    }
+
+    let mut sorter = Sorter::new()
+        .reverse(reverse)
+        .unique(m.opt_present("u"))
+        .stable(m.opt_present("s"));
+
+    if let Some(size) = m.opt_str("S") {
+        sorter = sorter.capacity(parse_size(&size)?);
+    }
+
+    let tmp_dir = m.opt_str("T");
+
+    if let Some(dir) = &tmp_dir {
+        sorter = sorter.temp_dir(dir);
+    }
+
+    let zero = m.opt_present("z");
+
+    if zero {
+        sorter = sorter.delimiter(b'\0');
+    }
+
+    let check = if m.opt_present("c") {
+        Some(false)
+    } else if m.opt_present("C") {
+        Some(true)
+    } else {
+        None
+    };
+
+    let output = m.opt_str("o");
+    let merge = m.opt_present("m");
+    let mut files = m.free;
+
+    if files.is_empty() {
+        files.push("-".to_string());
+    }
+
+    if check.is_some() && files.len() > 1 {
+        return Err(format!("extra operand '{}' not allowed with -c", files[1]));
+    }
+
+    Ok(Some(Options {
+        keys,
+        sep,
+        sorter,
+        tmp_dir,
+        zero,
+        output,
+        merge,
+        check,
+        files,
+    }))
+}
+
+// SIZE is a number followed by an optional unit; the default unit is K as in sort(1).
+fn parse_size(s: &str) -> Result<u64, String> {
+    let err = || format!("invalid buffer size '{}'", s);
+    let unit_at = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (num, unit) = s.split_at(unit_at);
+    let num: u64 = num.parse().map_err(|_| err())?;
+
+    let mul: u64 = match unit {
+        "b" => 1,
+        "" | "K" | "k" => 1 << 10,
+        "M" | "m" => 1 << 20,
+        "G" | "g" => 1 << 30,
+        "T" | "t" => 1 << 40,
+        _ => return Err(err()),
+    };
+
+    num.checked_mul(mul).ok_or_else(err)
+}
+
+fn key_of(keys: &[KeyDef], sep: Option<char>, delim: char, line: &str) -> Vec<Field> {
+    let line = line.strip_suffix(delim).unwrap_or(line);
+    keys.iter().map(|k| k.extract(line, sep)).collect()
+}
+
+fn open(file: &str) -> io::Result<Box<dyn io::Read>> {
+    if file == "-" {
+        Ok(Box::new(io::stdin()))
+    } else {
+        Ok(Box::new(fs::File::open(file)?))
+    }
+}
+
+fn tempfile(opts: &Options) -> io::Result<fs::File> {
+    match &opts.tmp_dir {
+        Some(dir) => tempfile::tempfile_in(dir),
+        None => tempfile::tempfile(),
+    }
+}
+
+// Append an input to a temporary file, terminating its last line if needed.
+fn spool(file: &str, tmp: &mut fs::File, delim: u8) -> io::Result<()> {
+    let mut reader = io::BufReader::new(open(file)?);
+    let mut writer = io::BufWriter::new(tmp);
+    let mut last = delim;
+
+    loop {
+        let buf = reader.fill_buf()?;
+
+        if buf.is_empty() {
+            break;
+        }
+
+        writer.write_all(buf)?;
+        last = buf[buf.len() - 1];
+        let n = buf.len();
+        reader.consume(n);
+    }
+
+    if last != delim {
+        writer.write_all(&[delim])?;
+    }
+
+    writer.flush()
+}
+
+fn is_output(opts: &Options, file: &str) -> bool {
+    let output = match &opts.output {
+        Some(output) => output,
+        None => return false,
+    };
+
+    match (fs::canonicalize(file), fs::canonicalize(output)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn run(opts: &Options) -> io::Result<bool> {
+    let delim = if opts.zero { b'\0' } else { b'\n' };
+    let key = |line: &String| key_of(&opts.keys, opts.sep, delim as char, line);
+
+    if let Some(quiet) = opts.check {
+        let file = &opts.files[0];
+
+        return match opts.sorter.check_sorted_by_key(open(file)?, key)? {
+            Some(d) => {
+                if !quiet {
+                    let line = d.line.strip_suffix(delim as char).unwrap_or(&d.line);
+                    eprintln!("exsort: {}:{}: disorder: {}", file, d.line_num, line);
+                }
+
+                Ok(false)
+            }
+            None => Ok(true),
+        };
+    }
+
+    let mut inputs = vec![];
+
+    if opts.merge {
+        for file in &opts.files {
+            if is_output(opts, file) {
+                let mut tmp = tempfile(opts)?;
+                spool(file, &mut tmp, delim)?;
+                tmp.seek(io::SeekFrom::Start(0))?;
+                inputs.push(Box::new(tmp) as Box<dyn io::Read>);
+            } else {
+                inputs.push(open(file)?);
+            }
+        }
+    }
+
+    let fin = if opts.merge {
+        None
+    } else if opts.files.len() == 1 && opts.files[0] != "-" && !is_output(opts, &opts.files[0]) {
+        Some(fs::File::open(&opts.files[0])?)
+    } else {
+        let mut tmp = tempfile(opts)?;
+
+        for file in &opts.files {
+            spool(file, &mut tmp, delim)?;
+        }
+
+        tmp.seek(io::SeekFrom::Start(0))?;
+        Some(tmp)
+    };
+
+    let fout: Box<dyn io::Write> = match &opts.output {
+        Some(output) => Box::new(fs::File::create(output)?),
+        None => Box::new(io::stdout().lock()),
+    };
+
+    match fin {
+        Some(fin) => opts.sorter.sort_by_key(fin, fout, key)?,
+        None => opts.sorter.merge_by_key(inputs, fout, key)?,
+    };
+
+    Ok(true)
+}
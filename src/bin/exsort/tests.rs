@@ -0,0 +1,66 @@
+use super::key::KeyDef;
+use super::key::Num;
+use super::parse_size;
+
+fn key_text<'a>(def: &str, line: &'a str, sep: Option<char>) -> &'a str {
+    &line[KeyDef::parse(def).unwrap().range(line, sep)]
+}
+
+#[test]
+fn test_parse_key_def() {
+    let key = KeyDef::parse("2.3b,4.5nr").unwrap();
+
+    assert_eq!(2, key.start_field);
+    assert_eq!(3, key.start_char);
+    assert!(key.start_blanks);
+    assert_eq!(Some(4), key.end_field);
+    assert_eq!(5, key.end_char);
+    assert!(!key.end_blanks);
+    assert!(key.numeric);
+    assert!(key.reverse);
+    assert!(key.has_opts);
+
+    let key = KeyDef::parse("3").unwrap();
+    assert_eq!(3, key.start_field);
+    assert_eq!(None, key.end_field);
+    assert!(!key.has_opts);
+
+    assert!(KeyDef::parse("0").is_err());
+    assert!(KeyDef::parse("1.0").is_err());
+    assert!(KeyDef::parse("1x").is_err());
+    assert!(KeyDef::parse("a,2").is_err());
+}
+
+#[test]
+fn test_extract_key() {
+    assert_eq!(" b", key_text("2,2", "a b  c", None));
+    assert_eq!("b", key_text("2b,2", "a b  c", None));
+    assert_eq!(" b  c", key_text("2", "a b  c", None));
+    assert_eq!("  c", key_text("3,3", "a b  c", None));
+    assert_eq!("", key_text("4,4", "a b  c", None));
+    assert_eq!("bc", key_text("2.2,2.3", "a,abcd,e", Some(',')));
+    assert_eq!("", key_text("2,2", "a,,e", Some(',')));
+    assert_eq!("abcd,e", key_text("2", "a,abcd,e", Some(',')));
+}
+
+#[test]
+fn test_numeric_order() {
+    let mut nums = vec![
+        "10", "-1.5", "abc", "2", "-10", "0.25", "-0", "007", "1e3", ".5",
+    ];
+    nums.sort_by_key(|n| Num::parse(n));
+
+    assert_eq!(
+        vec!["-10", "-1.5", "abc", "-0", "0.25", ".5", "1e3", "2", "007", "10"],
+        nums
+    );
+}
+
+#[test]
+fn test_parse_size() {
+    assert_eq!(Ok(10), parse_size("10b"));
+    assert_eq!(Ok(10 * 1024), parse_size("10"));
+    assert_eq!(Ok(3 * 1024 * 1024), parse_size("3M"));
+    assert!(parse_size("10X").is_err());
+    assert!(parse_size("M").is_err());
+}
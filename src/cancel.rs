@@ -0,0 +1,64 @@
+use super::Error;
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+/// Stops a running sort when `cancel` is called from another thread.
+///
+/// The sort then fails with `Error::Cancelled`.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+    parent: Option<Box<CancelToken>>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed) || self.parent.as_ref().is_some_and(|p| p.is_cancelled())
+    }
+
+    #[cfg(feature = "async")]
+    // A token that is cancelled together with `self`, but can also be cancelled on its own.
+    pub(crate) fn child(&self) -> CancelToken {
+        CancelToken {
+            flag: Arc::default(),
+            parent: Some(Box::new(self.clone())),
+        }
+    }
+}
+
+// Cancellation raised inside `io::Write` impls, turned into `Error::Cancelled` by `Error::write`.
+#[derive(Debug)]
+struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("sort cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+pub(crate) fn cancelled() -> io::Error {
+    io::Error::other(Cancelled)
+}
+
+pub(crate) fn is_cancelled_io(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|e| e.is::<Cancelled>())
+}
+
+/// Whether `err` was returned because the sort was cancelled.
+pub fn is_cancelled(err: &Error) -> bool {
+    matches!(err, Error::Cancelled)
+}
@@ -0,0 +1,51 @@
+use super::error::Result;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::order::Order;
+use std::io;
+
+/// The first line that is out of order.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Disorder {
+    /// 1-based line number.
+    pub line_num: u64,
+    /// Byte offset of the start of the line.
+    pub offset: u64,
+    pub line: String,
+}
+
+pub(crate) fn check_sorted<R, F, K>(
+    fin: R,
+    order: &Order,
+    delim: u8,
+    key: &F,
+) -> Result<Option<Disorder>>
+where
+    R: io::Read,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let mut records = Records::new(io::BufReader::new(fin), delim, Source::Input);
+    let mut prev: Option<(K, String)> = None;
+    let mut buf = String::new();
+    let mut offset = 0;
+
+    while records.read(&mut buf)? > 0 {
+        let k = key(&buf);
+
+        if let Some((pk, pl)) = &prev {
+            if !order.in_order(pk, pl, &k, &buf) {
+                return Ok(Some(Disorder {
+                    line_num: records.line_num(),
+                    offset,
+                    line: buf,
+                }));
+            }
+        }
+
+        offset += buf.len() as u64;
+        prev = Some((k, std::mem::take(&mut buf)));
+    }
+
+    Ok(None)
+}
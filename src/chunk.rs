@@ -1,71 +1,244 @@
+use super::context::Context;
+use super::error::Error;
+use super::error::Result;
 use super::file_utils;
 use super::slice_utils;
+use file_utils::Records;
 use file_utils::RoughCount;
-use io::prelude::BufRead;
+use file_utils::Source;
 use std::fs;
 use std::io;
 use std::io::Seek;
 use std::io::Write;
+#[cfg(feature = "mmap")]
+use std::str;
+use std::time::Instant;
 
-pub(super) struct Chunk {
+pub(super) struct Chunk<'a> {
     pub(super) file: fs::File,
-    pub(super) capacity: u64,
+    pub(super) ctx: &'a Context<'a>,
     pub(super) rough_count: file_utils::RoughCount,
+    // Position in the tree of splits, see `work_dir`.
+    pub(super) id: String,
+    // The input of the sort or a temporary file.
+    source: Source,
 }
 
-impl Chunk {
-    pub(super) fn new(f: fs::File, cap: u64) -> io::Result<Chunk> {
-        let rc = file_utils::count_roughly(&f)?;
+impl<'a> Chunk<'a> {
+    pub(super) fn new(f: fs::File, ctx: &'a Context<'a>, id: String) -> Result<Chunk<'a>> {
+        Chunk::with_source(f, ctx, id, Source::Temp)
+    }
+
+    // The root of the tree of splits, reading the input of the sort.
+    pub(super) fn input(f: fs::File, ctx: &'a Context<'a>) -> Result<Chunk<'a>> {
+        Chunk::with_source(f, ctx, "r".to_string(), Source::Input)
+    }
+
+    fn with_source(
+        f: fs::File,
+        ctx: &'a Context<'a>,
+        id: String,
+        source: Source,
+    ) -> Result<Chunk<'a>> {
+        let rc = file_utils::count_roughly(&f, ctx.sorter.delim).map_err(|e| source.error(e))?;
 
         Ok(Chunk {
-            capacity: cap,
             file: f,
+            ctx,
             rough_count: rc,
+            id,
+            source,
         })
     }
 
-    pub(super) fn fit_in_buffer(&self) -> bool {
-        self.file.metadata().unwrap().len() <= self.capacity
+    pub(super) fn len(&self) -> Result<u64> {
+        let meta = self.file.metadata().map_err(|e| self.source.error(e))?;
+        Ok(meta.len())
+    }
+
+    pub(super) fn fit_in_buffer(&self) -> Result<bool> {
+        Ok(self.len()? <= self.ctx.sorter.cap)
+    }
+
+    pub(super) fn records(&self) -> ChunkRecords<'_, 'a> {
+        let records = Records::new(
+            io::BufReader::new(&self.file),
+            self.ctx.sorter.delim,
+            self.source,
+        );
+
+        ChunkRecords {
+            records,
+            ctx: self.ctx,
+            input: self.source == Source::Input,
+        }
+    }
+
+    // Sample the lines of a chunk that is a run without being sorted.
+    pub(super) fn sample(&self) -> Result<()> {
+        if self.ctx.sampler.is_none() {
+            return Ok(());
+        }
+
+        let mut records = self.records();
+        let mut buf = String::new();
+
+        while records.read(&mut buf)? > 0 {
+            self.ctx.sample(&buf);
+            buf.clear();
+        }
+
+        self.rewind()
+    }
+
+    // Seek back to the start after reading the chunk.
+    pub(super) fn rewind(&self) -> Result<()> {
+        (&self.file)
+            .seek(io::SeekFrom::Start(0))
+            .map_err(|e| self.source.error(e))?;
+
+        Ok(())
     }
 
-    pub(super) fn sort<F, K>(&self, desc: bool, key: &F) -> io::Result<Chunk>
+    pub(super) fn sort<F, K>(&self, key: &F) -> Result<Chunk<'a>>
     where
         F: Fn(&String) -> K,
         K: Ord,
     {
-        let mut reader = io::BufReader::new(&self.file);
+        let order = self.ctx.sorter.order();
+        let mut run = RunWriter::new(self.ctx, self.id.clone())?;
+        let mut records = self.records();
         let mut lines = vec![];
         let mut buf = String::new();
 
-        while reader.read_line(&mut buf)? > 0 {
+        while records.read(&mut buf)? > 0 {
+            self.ctx.check_cancelled()?;
+            run.count(buf.len());
+            self.ctx.sample(&buf);
             lines.push(buf.clone());
             buf.clear();
         }
 
-        slice_utils::sort_by_cached_key(&mut lines, desc, key);
-        let mut writer = io::BufWriter::new(tempfile::tempfile()?);
+        slice_utils::sort_by_cached_key(&mut lines, key, |k1, l1, k2, l2| {
+            order.cmp(k1, l1, k2, l2)
+        });
+        let mut prev = None;
 
         for l in lines {
-            writer.write(l.as_bytes())?;
+            if order.unique {
+                let k = key(&l);
+
+                if prev.as_ref().is_some_and(|p| order.is_dup(p, &k)) {
+                    continue;
+                }
+
+                prev = Some(k);
+            }
+
+            run.write(l.as_bytes())?;
         }
 
-        writer.seek(io::SeekFrom::Start(0))?;
-        Chunk::new(writer.into_inner().unwrap(), self.capacity)
+        let bytes = run.bytes;
+        run.finish(bytes)
     }
 
-    pub(super) fn split(&self) -> io::Result<(Chunk, Chunk)> {
+    // Sorted runs of about `capacity` bytes each, in input order, sorting the positions of
+    // the lines in a memory map of the chunk so that each line is copied only when its run
+    // is written.
+    #[cfg(feature = "mmap")]
+    pub(super) fn mapped_runs<F, K>(&self, key: &F) -> Result<Vec<Chunk<'a>>>
+    where
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let cap = self.ctx.sorter.cap;
+        let delim = self.ctx.sorter.delim;
+        // The chunk is a temporary file, or the input which the caller must not modify.
+        let map = unsafe { memmap2::Mmap::map(&self.file) }.map_err(|e| self.source.error(e))?;
+        let mut runs = vec![];
+        let mut run = None;
+        let mut entries = vec![];
+        let mut buf = String::new();
+        let mut offset = 0;
+        let mut line_num = 0;
+
+        while offset < map.len() {
+            self.ctx.check_cancelled()?;
+            let len = match map[offset..].iter().position(|&b| b == delim) {
+                Some(n) => n + 1,
+                None => map.len() - offset,
+            };
+            let line =
+                str::from_utf8(&map[offset..offset + len]).map_err(|_| Error::InvalidEncoding {
+                    line_num: line_num + 1,
+                    offset: offset as u64,
+                })?;
+
+            buf.clear();
+            buf.push_str(line);
+
+            if !line.ends_with(char::from(delim)) {
+                buf.push(char::from(delim));
+            }
+
+            line_num += 1;
+
+            if self.source == Source::Input && !self.ctx.accept(&buf, line_num, offset as u64)? {
+                offset += len;
+                continue;
+            }
+
+            // A single line longer than `capacity` is a run too.
+            if run
+                .as_ref()
+                .is_some_and(|r: &RunWriter| r.bytes + len as u64 > cap)
+            {
+                if let Some(r) = run.take() {
+                    runs.push(write_mapped(&map, r, &mut entries)?);
+                }
+            }
+
+            let r = match &mut run {
+                Some(r) => r,
+                None => {
+                    let id = format!("{}.m{}", self.id, runs.len());
+                    run.insert(RunWriter::new(self.ctx, id)?)
+                }
+            };
+
+            self.ctx.sample(&buf);
+            r.count(len);
+            entries.push((key(&buf), offset, len));
+            offset += len;
+        }
+
+        if let Some(r) = run {
+            runs.push(write_mapped(&map, r, &mut entries)?);
+        }
+
+        Ok(runs)
+    }
+
+    pub(super) fn split(&self) -> Result<(Chunk<'a>, Chunk<'a>)> {
         assert!(self.rough_count == RoughCount::Two || self.rough_count == RoughCount::ThreeOrMore);
 
-        let mid = self.file.metadata().unwrap().len() / 2;
-        let mut reader = io::BufReader::new(&self.file);
-        let mut writer1 = io::BufWriter::new(tempfile::tempfile().unwrap());
-        let mut writer2 = io::BufWriter::new(tempfile::tempfile().unwrap());
+        let start = Instant::now();
+        let len = self.len()?;
+        let mid = len / 2;
+        let mut records = self.records();
+        let id1 = format!("{}0", self.id);
+        let id2 = format!("{}1", self.id);
+        let mut writer1 = io::BufWriter::new(self.ctx.create_file(&format!("{}.in", id1))?);
+        let mut writer2 = io::BufWriter::new(self.ctx.create_file(&format!("{}.in", id2))?);
         let mut sum = 0;
         let mut buf = String::new();
 
-        while reader.read_line(&mut buf)? > 0 {
+        while records.read(&mut buf)? > 0 {
+            self.ctx.check_cancelled()?;
             sum += buf.len() as u64;
-            writer1.write(buf.as_bytes())?;
+            writer1
+                .write_all(buf.as_bytes())
+                .map_err(Error::TempSpace)?;
             buf.clear();
 
             if sum >= mid || self.rough_count == RoughCount::Two {
@@ -73,17 +246,165 @@ impl Chunk {
             }
         }
 
-        while reader.read_line(&mut buf)? > 0 {
-            writer2.write(buf.as_bytes())?;
+        while records.read(&mut buf)? > 0 {
+            self.ctx.check_cancelled()?;
+            writer2
+                .write_all(buf.as_bytes())
+                .map_err(Error::TempSpace)?;
             buf.clear();
         }
 
-        writer1.seek(io::SeekFrom::Start(0))?;
-        writer2.seek(io::SeekFrom::Start(0))?;
+        let f1 = rewind(writer1)?;
+        let f2 = rewind(writer2)?;
+        self.ctx
+            .commit(&[&f1, &f2], &format!("split {}", self.id))?;
+
+        self.ctx.stats(|s| {
+            s.bytes_spilled += len;
+            s.run_duration += start.elapsed();
+        });
 
         Ok((
-            Chunk::new(writer1.into_inner().unwrap(), self.capacity)?,
-            Chunk::new(writer2.into_inner().unwrap(), self.capacity)?,
+            Chunk::new(f1, self.ctx, id1)?,
+            Chunk::new(f2, self.ctx, id2)?,
         ))
     }
 }
+
+// Records of a chunk, leaving out the lines of the input that `Context::accept` rejects.
+pub(super) struct ChunkRecords<'c, 'a> {
+    records: Records<io::BufReader<&'c fs::File>>,
+    ctx: &'a Context<'a>,
+    input: bool,
+}
+
+impl ChunkRecords<'_, '_> {
+    pub(super) fn read(&mut self, buf: &mut String) -> Result<usize> {
+        loop {
+            let offset = self.records.offset();
+            let n = self.records.read(buf)?;
+
+            if n == 0 || !self.input || self.ctx.accept(buf, self.records.line_num(), offset)? {
+                return Ok(n);
+            }
+
+            buf.clear();
+        }
+    }
+
+    // Records read so far, including rejected ones.
+    pub(super) fn line_num(&self) -> u64 {
+        self.records.line_num()
+    }
+
+    pub(super) fn offset(&self) -> u64 {
+        self.records.offset()
+    }
+}
+
+// A sorted run being written to `<id>.run`, and what is counted for its stats.
+pub(super) struct RunWriter<'a> {
+    ctx: &'a Context<'a>,
+    id: String,
+    writer: io::BufWriter<fs::File>,
+    // Records and bytes read from the input into the run.
+    pub(super) records: u64,
+    pub(super) bytes: u64,
+    spilled: u64,
+    start: Instant,
+}
+
+impl<'a> RunWriter<'a> {
+    pub(super) fn new(ctx: &'a Context<'a>, id: String) -> Result<RunWriter<'a>> {
+        let f = ctx.create_file(&format!("{}.run", id))?;
+
+        Ok(RunWriter {
+            ctx,
+            id,
+            writer: io::BufWriter::new(f),
+            records: 0,
+            bytes: 0,
+            spilled: 0,
+            start: Instant::now(),
+        })
+    }
+
+    // Count a record of `len` bytes read into the run, whether or not it is written.
+    pub(super) fn count(&mut self, len: usize) {
+        self.records += 1;
+        self.bytes += len as u64;
+    }
+
+    pub(super) fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.writer.write_all(buf).map_err(Error::TempSpace)?;
+        self.spilled += buf.len() as u64;
+        Ok(())
+    }
+
+    // Commit the run, which was generated holding at most `buffered` bytes of records.
+    pub(super) fn finish(self, buffered: u64) -> Result<Chunk<'a>> {
+        let run = rewind(self.writer)?;
+        self.ctx.commit(&[&run], &format!("run {}", self.id))?;
+        self.ctx.run_produced(self.records, self.bytes, buffered);
+
+        let (spilled, start) = (self.spilled, self.start);
+
+        self.ctx.stats(|s| {
+            s.bytes_spilled += spilled;
+            s.run_duration += start.elapsed();
+        });
+
+        Chunk::new(run, self.ctx, self.id)
+    }
+}
+
+// Sort the entries of a run of `map`, each a key with the offset and length of its line,
+// and write their lines.
+#[cfg(feature = "mmap")]
+fn write_mapped<'a, K: Ord>(
+    map: &[u8],
+    mut run: RunWriter<'a>,
+    entries: &mut Vec<(K, usize, usize)>,
+) -> Result<Chunk<'a>> {
+    let order = run.ctx.sorter.order();
+    let delim = run.ctx.sorter.delim;
+    let line = |offset: usize, len: usize| {
+        // Checked by `Chunk::mapped_runs`.
+        str::from_utf8(&map[offset..offset + len]).unwrap_or_default()
+    };
+
+    entries.sort_by(|(k1, o1, l1), (k2, o2, l2)| order.cmp(k1, line(*o1, *l1), k2, line(*o2, *l2)));
+
+    let mut prev: Option<&K> = None;
+
+    for (k, offset, len) in entries.iter() {
+        if order.unique {
+            if prev.is_some_and(|p| order.is_dup(p, k)) {
+                continue;
+            }
+
+            prev = Some(k);
+        }
+
+        let record = &map[*offset..*offset + *len];
+        run.write(record)?;
+
+        if record.last() != Some(&delim) {
+            run.write(&[delim])?;
+        }
+    }
+
+    entries.clear();
+    let bytes = run.bytes;
+    run.finish(bytes)
+}
+
+// Flush a temporary file and seek back to its start for reading.
+pub(super) fn rewind(mut writer: io::BufWriter<fs::File>) -> Result<fs::File> {
+    writer
+        .seek(io::SeekFrom::Start(0))
+        .map_err(Error::TempSpace)?;
+    writer
+        .into_inner()
+        .map_err(|e| Error::TempSpace(e.into_error()))
+}
@@ -0,0 +1,279 @@
+use super::cancel;
+use super::chunk;
+use super::error::Error;
+use super::error::Result;
+use super::file_utils;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::progress::Progress;
+use super::range::Sampler;
+use super::stats::SortStats;
+use super::work_dir::WorkDir;
+use super::work_dir::SAMPLES;
+use super::Sorter;
+use std::cell::OnceCell;
+use std::cell::RefCell;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+
+// Report `bytes_written` at most once per this many bytes.
+const WRITE_INTERVAL: u64 = 1024 * 1024;
+
+// Decides whether a line of the input, given with its line number and offset, is sorted.
+pub(crate) type Filter<'a> = &'a dyn Fn(&str, u64, u64) -> Result<bool>;
+
+// State of a single sort or merge call.
+pub(crate) struct Context<'a> {
+    pub(crate) sorter: &'a Sorter,
+    progress: RefCell<Progress>,
+    stats: RefCell<SortStats>,
+    pub(crate) work_dir: Option<WorkDir>,
+    pub(crate) sampler: Option<RefCell<Sampler>>,
+    pub(crate) filter: Option<Filter<'a>>,
+    temp_dir: OnceCell<tempfile::TempDir>,
+}
+
+impl<'a> Context<'a> {
+    pub(crate) fn new(sorter: &'a Sorter, input_bytes: u64) -> Context<'a> {
+        Context {
+            sorter,
+            progress: RefCell::new(Progress {
+                input_bytes,
+                ..Progress::default()
+            }),
+            stats: RefCell::new(SortStats::default()),
+            work_dir: None,
+            sampler: None,
+            filter: None,
+            temp_dir: OnceCell::new(),
+        }
+    }
+
+    // Files are created in the work directory, or else in a temporary directory that is
+    // removed with the context, so that runs can be closed until they are merged.
+    pub(crate) fn create_file(&self, name: &str) -> Result<fs::File> {
+        let f = match &self.work_dir {
+            Some(wd) => wd.create(name),
+            None => self
+                .temp_dir()
+                .and_then(|dir| file_utils::create(&dir.join(name))),
+        };
+
+        f.map_err(Error::TempSpace)
+    }
+
+    pub(crate) fn open_file(&self, name: &str) -> Result<fs::File> {
+        let f = match &self.work_dir {
+            Some(wd) => wd.open_file(name),
+            None => self
+                .temp_dir()
+                .and_then(|dir| file_utils::open(&dir.join(name))),
+        };
+
+        f.map_err(Error::TempSpace)
+    }
+
+    pub(crate) fn remove_file(&self, name: &str) -> Result<()> {
+        let removed = match &self.work_dir {
+            Some(wd) => wd.remove(name),
+            None => self
+                .temp_dir()
+                .and_then(|dir| file_utils::remove(&dir.join(name))),
+        };
+
+        removed.map_err(Error::TempSpace)
+    }
+
+    fn temp_dir(&self) -> io::Result<&Path> {
+        if let Some(dir) = self.temp_dir.get() {
+            return Ok(dir.path());
+        }
+
+        let dir = match &self.sorter.tmp_dir {
+            Some(dir) => tempfile::tempdir_in(dir)?,
+            None => tempfile::tempdir()?,
+        };
+
+        Ok(self.temp_dir.get_or_init(|| dir).path())
+    }
+
+    pub(crate) fn commit(&self, files: &[&fs::File], step: &str) -> Result<()> {
+        match &self.work_dir {
+            Some(wd) => wd.commit(files, step).map_err(Error::TempSpace),
+            None => Ok(()),
+        }
+    }
+
+    // Whether a line of the input goes into the sort, counting the lines left out.
+    pub(crate) fn accept(&self, line: &str, line_num: u64, offset: u64) -> Result<bool> {
+        let accepted = match &self.filter {
+            Some(filter) => filter(line, line_num, offset)?,
+            None => true,
+        };
+
+        if !accepted {
+            self.stats(|s| s.rejected += 1);
+        }
+
+        Ok(accepted)
+    }
+
+    // Called for every line that goes into a run.
+    pub(crate) fn sample(&self, line: &str) {
+        if let Some(sampler) = &self.sampler {
+            sampler.borrow_mut().add(line);
+        }
+    }
+
+    // Keep the samples in the work directory, so that a sort resumed after all the runs
+    // were generated has the samples of the runs it does not read again.
+    pub(crate) fn commit_samples(&self) -> Result<()> {
+        let (wd, sampler) = match (&self.work_dir, &self.sampler) {
+            (Some(wd), Some(sampler)) => (wd, sampler),
+            _ => return Ok(()),
+        };
+
+        let mut writer = io::BufWriter::new(wd.create(SAMPLES).map_err(Error::TempSpace)?);
+
+        for line in &sampler.borrow().lines {
+            writer
+                .write_all(line.as_bytes())
+                .map_err(Error::TempSpace)?;
+        }
+
+        let f = chunk::rewind(writer)?;
+        self.commit(&[&f], "samples")
+    }
+
+    // Restore the samples committed by an interrupted sort.
+    pub(crate) fn resume_samples(&self) -> Result<()> {
+        let (wd, sampler) = match (&self.work_dir, &self.sampler) {
+            (Some(wd), Some(sampler)) if wd.has_samples() => (wd, sampler),
+            _ => return Ok(()),
+        };
+
+        let f = wd.open_file(SAMPLES).map_err(Error::TempSpace)?;
+        let mut records = Records::new(io::BufReader::new(f), self.sorter.delim, Source::Temp);
+        let mut lines = vec![];
+        let mut buf = String::new();
+
+        while records.read(&mut buf)? > 0 {
+            lines.push(std::mem::take(&mut buf));
+        }
+
+        sampler.borrow_mut().lines = lines;
+        Ok(())
+    }
+
+    pub(crate) fn samples(&self) -> Vec<String> {
+        match &self.sampler {
+            Some(sampler) => std::mem::take(&mut sampler.borrow_mut().lines),
+            None => vec![],
+        }
+    }
+
+    pub(crate) fn check_cancelled(&self) -> Result<()> {
+        match &self.sorter.cancel {
+            Some(token) if token.is_cancelled() => Err(Error::Cancelled),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn into_stats(self) -> SortStats {
+        self.stats.into_inner()
+    }
+
+    pub(crate) fn stats<F>(&self, f: F)
+    where
+        F: FnOnce(&mut SortStats),
+    {
+        f(&mut self.stats.borrow_mut());
+    }
+
+    // A sorted run of `records` lines and `bytes` bytes was produced from the input,
+    // holding at most `buffered` bytes of them in memory.
+    pub(crate) fn run_produced(&self, records: u64, bytes: u64, buffered: u64) {
+        self.stats(|s| {
+            s.runs += 1;
+            s.records += records;
+            s.peak_buffered_bytes = s.peak_buffered_bytes.max(buffered);
+        });
+
+        self.update(|p| {
+            p.runs += 1;
+            p.bytes_read += bytes;
+        });
+    }
+
+    pub(crate) fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Progress),
+    {
+        let mut progress = self.progress.borrow_mut();
+        f(&mut progress);
+
+        if let Some(observer) = &self.sorter.observer {
+            (observer.0)(&progress);
+        }
+    }
+
+    // Same as `update` without calling the observer, for counters that change on every line.
+    pub(crate) fn update_quietly<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Progress),
+    {
+        f(&mut self.progress.borrow_mut());
+    }
+
+    pub(crate) fn writer<'b, W: io::Write>(&'b self, inner: W) -> ProgressWriter<'b, W> {
+        ProgressWriter {
+            inner,
+            ctx: self,
+            unreported: 0,
+        }
+    }
+}
+
+// Counts the bytes written to the output.
+pub(crate) struct ProgressWriter<'a, W> {
+    inner: W,
+    ctx: &'a Context<'a>,
+    unreported: u64,
+}
+
+impl<'a, W> ProgressWriter<'a, W> {
+    fn report(&mut self) {
+        let n = self.unreported;
+        self.unreported = 0;
+        self.ctx.update(|p| p.bytes_written += n);
+    }
+}
+
+impl<'a, W: io::Write> io::Write for ProgressWriter<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.ctx.check_cancelled().is_err() {
+            return Err(cancel::cancelled());
+        }
+
+        let n = self.inner.write(buf)?;
+        self.unreported += n as u64;
+
+        if self.unreported >= WRITE_INTERVAL {
+            self.report();
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()?;
+
+        if self.unreported > 0 {
+            self.report();
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,90 @@
+use super::cancel;
+use std::error;
+use std::fmt;
+use std::io;
+
+/// Errors returned by the functions of this crate.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading an input failed.
+    Read(io::Error),
+    /// Line `line_num` of an input, starting at byte `offset`, is not valid UTF-8.
+    InvalidEncoding { line_num: u64, offset: u64 },
+    /// Creating, writing or reading a temporary file failed,
+    /// or `work_dir` belongs to a sort with other settings.
+    TempSpace(io::Error),
+    /// Writing the output failed.
+    Write(io::Error),
+    /// The key function failed on line `line_num` of an input, starting at byte `offset`.
+    Key {
+        line_num: u64,
+        offset: u64,
+        source: Box<dyn error::Error + Send + Sync>,
+    },
+    /// Input `input` of `merge_by_key` is not sorted at line `line_num`.
+    /// Returned only with `verify`.
+    NotSorted { input: usize, line_num: u64 },
+    /// The call was stopped by a `CancelToken`.
+    Cancelled,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    // Output writers report cancellation as an `io::Error`, see `Context::writer`.
+    pub(crate) fn write(e: io::Error) -> Error {
+        if cancel::is_cancelled_io(&e) {
+            Error::Cancelled
+        } else {
+            Error::Write(e)
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Read(e) => write!(f, "failed to read input: {}", e),
+            Error::InvalidEncoding { line_num, offset } => {
+                write!(f, "line {} at byte {} is not valid UTF-8", line_num, offset)
+            }
+            Error::TempSpace(e) => write!(f, "failed to use temporary file: {}", e),
+            Error::Write(e) => write!(f, "failed to write output: {}", e),
+            Error::Key {
+                line_num,
+                offset,
+                source,
+            } => write!(
+                f,
+                "key function failed at line {} at byte {}: {}",
+                line_num, offset, source
+            ),
+            Error::NotSorted { input, line_num } => {
+                write!(f, "input {} is not sorted at line {}", input, line_num)
+            }
+            Error::Cancelled => f.write_str("sort cancelled"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Read(e) | Error::TempSpace(e) | Error::Write(e) => Some(e),
+            Error::Key { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        let kind = match &e {
+            Error::Read(e) | Error::TempSpace(e) | Error::Write(e) => e.kind(),
+            Error::InvalidEncoding { .. } | Error::NotSorted { .. } => io::ErrorKind::InvalidData,
+            Error::Key { .. } | Error::Cancelled => io::ErrorKind::Other,
+        };
+
+        io::Error::new(kind, e)
+    }
+}
@@ -1,8 +1,11 @@
+use super::error::Error;
+use super::error::Result;
 use io::prelude::BufRead;
 use std::fs;
 use std::io;
 use std::io::Seek;
 use std::io::Write;
+use std::path::Path;
 
 #[derive(Debug, PartialEq)]
 pub(crate) enum RoughCount {
@@ -12,12 +15,142 @@ pub(crate) enum RoughCount {
     ThreeOrMore,
 }
 
-pub(crate) fn count_roughly(f: &fs::File) -> io::Result<RoughCount> {
+// Where records are read from, to tell input errors from temp-space errors.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Source {
+    Input,
+    Temp,
+}
+
+impl Source {
+    pub(crate) fn error(self, e: io::Error) -> Error {
+        match self {
+            Source::Input => Error::Read(e),
+            Source::Temp => Error::TempSpace(e),
+        }
+    }
+}
+
+// Reads records ending with `delim`, counting them to locate invalid UTF-8.
+pub(crate) struct Records<R> {
+    reader: R,
+    delim: u8,
+    source: Source,
+    line_num: u64,
+    offset: u64,
+}
+
+impl<R: BufRead> Records<R> {
+    pub(crate) fn new(reader: R, delim: u8, source: Source) -> Records<R> {
+        Records {
+            reader,
+            delim,
+            source,
+            line_num: 0,
+            offset: 0,
+        }
+    }
+
+    // Number of records read so far.
+    pub(crate) fn line_num(&self) -> u64 {
+        self.line_num
+    }
+
+    // Byte offset of the next record.
+    pub(crate) fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    // Same as `BufRead::read_line` except that records end with `delim`.
+    // A last record without `delim` gets one appended, so that it is not glued to
+    // the next record when it is written elsewhere than at the end.
+    // Returns the number of bytes read, which does not count the appended `delim`.
+    pub(crate) fn read(&mut self, buf: &mut String) -> Result<usize> {
+        let mut bytes = std::mem::take(buf).into_bytes();
+        let source = self.source;
+        let n = self
+            .reader
+            .read_until(self.delim, &mut bytes)
+            .map_err(|e| source.error(e))?;
+
+        if n > 0 {
+            self.line_num += 1;
+        }
+
+        *buf = String::from_utf8(bytes).map_err(|_| Error::InvalidEncoding {
+            line_num: self.line_num,
+            offset: self.offset,
+        })?;
+
+        self.offset += n as u64;
+
+        if n > 0 && buf.as_bytes().last() != Some(&self.delim) {
+            buf.push(char::from(self.delim));
+        }
+
+        Ok(n)
+    }
+}
+
+// Reads a file from `offset` on with positional reads, so that readers of the same file
+// do not move each other's position.
+pub(crate) struct ReadAt<'a> {
+    file: &'a fs::File,
+    offset: u64,
+}
+
+impl<'a> ReadAt<'a> {
+    pub(crate) fn new(file: &'a fs::File, offset: u64) -> ReadAt<'a> {
+        ReadAt { file, offset }
+    }
+}
+
+impl io::Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(unix)]
+        let n = std::os::unix::fs::FileExt::read_at(self.file, buf, self.offset)?;
+        #[cfg(windows)]
+        let n = std::os::windows::fs::FileExt::seek_read(self.file, buf, self.offset)?;
+
+        self.offset += n as u64;
+        Ok(n)
+    }
+}
+
+pub(crate) fn tempfile(dir: Option<&Path>) -> io::Result<fs::File> {
+    match dir {
+        Some(dir) => tempfile::tempfile_in(dir),
+        None => tempfile::tempfile(),
+    }
+}
+
+// Files of a sort that are closed and opened again by name, see `Context::create_file`.
+pub(crate) fn create(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+pub(crate) fn open(path: &Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().read(true).write(true).open(path)
+}
+
+pub(crate) fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn count_roughly(f: &fs::File, delim: u8) -> io::Result<RoughCount> {
     let mut reader = io::BufReader::new(f);
-    let mut buf = String::new();
+    let mut buf = vec![];
     let mut n = 0;
 
-    while reader.read_line(&mut buf)? > 0 {
+    while reader.read_until(delim, &mut buf)? > 0 {
         buf.clear();
         n += 1;
 
@@ -38,18 +171,28 @@ pub(crate) fn count_roughly(f: &fs::File) -> io::Result<RoughCount> {
     Ok(rc)
 }
 
-pub(crate) fn copy<T>(fin: &fs::File, fout: T) -> io::Result<()>
+// Copy a temporary file to the output.
+pub(crate) fn copy<T>(fin: &fs::File, fout: T, delim: u8) -> Result<()>
+where
+    T: io::Write,
+{
+    copy_head(fin, fout, u64::MAX, delim)
+}
+
+pub(crate) fn copy_head<T>(fin: &fs::File, fout: T, n: u64, delim: u8) -> Result<()>
 where
     T: io::Write,
 {
-    let mut reader = io::BufReader::new(fin);
+    let mut records = Records::new(io::BufReader::new(fin), delim, Source::Temp);
     let mut writer = io::BufWriter::new(fout);
     let mut buf = String::new();
+    let mut i = 0;
 
-    while reader.read_line(&mut buf)? > 0 {
-        writer.write(buf.as_bytes())?;
+    while i < n && records.read(&mut buf)? > 0 {
+        writer.write_all(buf.as_bytes()).map_err(Error::write)?;
         buf.clear();
+        i += 1;
     }
 
-    Ok(())
+    writer.flush().map_err(Error::write)
 }
@@ -0,0 +1,175 @@
+use super::chunk::Chunk;
+use super::context::Context;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::merge::Merger;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorted;
+use super::Sorter;
+use std::fs;
+use std::io;
+
+/// Sorted lines grouped by key, returned by `sort_by_key_grouped`.
+///
+/// This is not an `Iterator` because each `Group` borrows it to read its lines
+/// from the last merge of the sort. Lines of a group that are not read are skipped.
+pub struct Groups<'a, F, K> {
+    // Set to `None` once every line is read, closing the runs.
+    lines: Option<Merger<Records<io::BufReader<fs::File>>, F, K>>,
+    // Holds the runs until they are read.
+    ctx: Context<'a>,
+    stats: SortStats,
+    // Key of the current group and its first line until it is read.
+    current: Option<K>,
+    first: Option<String>,
+    in_group: bool,
+    // The line read past the end of the current group.
+    next: Option<(K, String)>,
+}
+
+impl<'a, F, K> Groups<'a, F, K>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    /// Statistics of the sort.
+    pub fn stats(&self) -> &SortStats {
+        &self.stats
+    }
+
+    /// The next group, after skipping the lines left in the current one.
+    pub fn next_group(&mut self) -> Result<Option<Group<'_, 'a, F, K>>> {
+        while self.group_line()?.is_some() {}
+
+        let (k, line) = match self.next.take() {
+            Some(next) => next,
+            None => match self.read_line()? {
+                Some(next) => next,
+                None => return Ok(None),
+            },
+        };
+
+        self.current = Some(k);
+        self.first = Some(line);
+        self.in_group = true;
+        Ok(Some(Group { groups: self }))
+    }
+
+    fn group_line(&mut self) -> Result<Option<String>> {
+        if !self.in_group {
+            return Ok(None);
+        }
+
+        if let Some(line) = self.first.take() {
+            return Ok(Some(line));
+        }
+
+        match self.read_line()? {
+            Some((k, line)) if self.current.as_ref() == Some(&k) => Ok(Some(line)),
+            next => {
+                self.next = next;
+                self.in_group = false;
+                Ok(None)
+            }
+        }
+    }
+
+    fn read_line(&mut self) -> Result<Option<(K, String)>> {
+        self.ctx.check_cancelled()?;
+
+        let lines = match &mut self.lines {
+            Some(lines) => lines,
+            None => return Ok(None),
+        };
+
+        if let Some(line) = lines.next().transpose()? {
+            return Ok(Some((lines.key(&line), line)));
+        }
+
+        self.lines = None;
+
+        if let Some(wd) = self.ctx.work_dir.take() {
+            wd.finish().map_err(Error::TempSpace)?;
+        }
+
+        Ok(None)
+    }
+}
+
+/// Lines with equal keys, read from the last merge as they are iterated.
+pub struct Group<'g, 'a, F, K> {
+    groups: &'g mut Groups<'a, F, K>,
+}
+
+impl<F, K> Group<'_, '_, F, K> {
+    pub fn key(&self) -> &K {
+        // Set by `next_group` before a group is returned.
+        self.groups.current.as_ref().expect("a group has a key")
+    }
+}
+
+impl<F, K> Iterator for Group<'_, '_, F, K>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.groups.group_line().transpose()
+    }
+}
+
+impl Sorter {
+    /// Sort `fin` and return its lines grouped by key, in sorted order.
+    ///
+    /// Groups are read as the last runs are merged, so they may be larger than memory.
+    /// The runs are kept until the groups are dropped, and those in `work_dir` until
+    /// every group is read.
+    pub fn sort_by_key_grouped<F, K>(&self, fin: fs::File, key: F) -> Result<Groups<'_, F, K>>
+    where
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let ctx = self.context(&fin, None, None)?;
+
+        let files = match super::sort_runs(Chunk::input(fin, &ctx)?, &key)? {
+            Sorted::Chunk(chunk) => vec![chunk.file],
+            Sorted::Runs(last) => {
+                // The last merge is counted although it is done as the groups are read.
+                if last.runs.len() > 1 {
+                    ctx.update(|p| p.merge_pass = last.pass);
+                    ctx.stats(|s| {
+                        s.merges += 1;
+                        s.merge_passes = s.merge_passes.max(last.pass);
+                    });
+                }
+
+                last.runs
+                    .iter()
+                    .map(|r| ctx.open_file(&r.name))
+                    .collect::<Result<Vec<fs::File>>>()?
+            }
+        };
+
+        let readers = files
+            .into_iter()
+            .map(|f| Records::new(io::BufReader::new(f), self.delim, Source::Temp))
+            .collect();
+        let lines = Merger::new(readers, self.order(), false, key)?;
+        let mut stats = SortStats::default();
+        ctx.stats(|s| stats = s.clone());
+
+        Ok(Groups {
+            lines: Some(lines),
+            ctx,
+            stats,
+            current: None,
+            first: None,
+            in_group: false,
+            next: None,
+        })
+    }
+}
@@ -0,0 +1,377 @@
+use super::file_utils;
+use super::merge;
+use super::merge::Merger;
+use super::order::Order;
+use super::slice_utils;
+use super::CancelToken;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::borrow::Borrow;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use std::vec;
+
+/// Writes values to temporary files and reads them back for `sort_iter_by_key_with`.
+pub trait Codec<T> {
+    /// Append the encoding of `value` to `buf`.
+    /// Its length is also what counts towards `capacity`.
+    fn encode(&self, value: &T, buf: &mut Vec<u8>);
+
+    /// Read the next value, or `None` at the end of `reader`.
+    fn decode<R: BufRead>(&self, reader: &mut R) -> io::Result<Option<T>>;
+}
+
+/// Length-prefixed strings, so that values may contain any character.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StringCodec;
+
+impl Codec<String> for StringCodec {
+    fn encode(&self, value: &String, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        buf.extend_from_slice(value.as_bytes());
+    }
+
+    fn decode<R: BufRead>(&self, reader: &mut R) -> io::Result<Option<String>> {
+        if reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+
+        let mut len = [0; 8];
+        reader.read_exact(&mut len)?;
+        let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+        reader.read_exact(&mut bytes)?;
+
+        String::from_utf8(bytes)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+// A sorted run: the last one stays in memory and the others are spilled to closed files,
+// so that only the runs of a merge are open at once.
+enum Run<T> {
+    Memory(Vec<T>),
+    File(PathBuf),
+}
+
+// A run opened for a merge.
+struct RunReader<T, C> {
+    values: Values<T>,
+    codec: Arc<C>,
+    read: u64,
+}
+
+enum Values<T> {
+    Memory(vec::IntoIter<T>),
+    File(io::BufReader<fs::File>),
+}
+
+impl<T> Run<T> {
+    fn open<C>(self, codec: &Arc<C>) -> Result<RunReader<T, C>> {
+        let values = match self {
+            Run::Memory(values) => Values::Memory(values.into_iter()),
+            Run::File(path) => {
+                let f = file_utils::open(&path).map_err(Error::TempSpace)?;
+                Values::File(io::BufReader::new(f))
+            }
+        };
+
+        Ok(RunReader {
+            values,
+            codec: codec.clone(),
+            read: 0,
+        })
+    }
+}
+
+impl<T, C: Codec<T>> merge::Input for RunReader<T, C> {
+    type Value = T;
+
+    fn next(&mut self) -> Result<Option<T>> {
+        let value = match &mut self.values {
+            Values::Memory(values) => values.next(),
+            Values::File(reader) => self.codec.decode(reader).map_err(Error::TempSpace)?,
+        };
+
+        self.read += 1;
+        Ok(value)
+    }
+
+    fn line_num(&self) -> u64 {
+        self.read
+    }
+}
+
+/// Sorted values returned by `sort_iter_by_key`. Temporary files are removed when it is dropped.
+pub struct SortedIter<T, C: Codec<T>, F, K> {
+    cancel: Option<CancelToken>,
+    merger: Merger<RunReader<T, C>, F, K>,
+    stats: SortStats,
+    // Holds the spilled runs.
+    _dir: Option<tempfile::TempDir>,
+}
+
+impl<T, C: Codec<T>, F, K> SortedIter<T, C, F, K> {
+    /// Statistics of the runs and of the merges done before the values are read.
+    /// The last merge, done as the values are read, is counted too.
+    pub fn stats(&self) -> &SortStats {
+        &self.stats
+    }
+}
+
+impl<T, C, F, K> Iterator for SortedIter<T, C, F, K>
+where
+    C: Codec<T>,
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.cancel.as_ref().is_some_and(|t| t.is_cancelled()) {
+            return Some(Err(Error::Cancelled));
+        }
+
+        self.merger.next()
+    }
+}
+
+impl Sorter {
+    /// Sort values that do not fit in `capacity` bytes of memory, as encoded by `codec`.
+    /// Values with equal keys keep their input order; `stable(false)` does not apply.
+    ///
+    /// Sorted runs are written to temporary files, except the last one which stays
+    /// in memory, and merged as the returned iterator is consumed. When there are more
+    /// than `max_fan_in` runs, some are merged beforehand so that at most `max_fan_in`
+    /// files are open at once.
+    pub fn sort_iter_by_key_with<I, T, C, F, K>(
+        &self,
+        iter: I,
+        codec: C,
+        key: F,
+    ) -> Result<SortedIter<T, C, F, K>>
+    where
+        I: IntoIterator<Item = T>,
+        C: Codec<T>,
+        F: Fn(&T) -> K,
+        K: Ord,
+    {
+        let start = Instant::now();
+        let mut stats = SortStats::default();
+        let mut dir = None;
+        let mut runs = vec![];
+        let mut lens = vec![];
+        let mut values = vec![];
+        let mut bytes = 0;
+        let mut buf = vec![];
+
+        for value in iter {
+            if self.cancel.as_ref().is_some_and(|t| t.is_cancelled()) {
+                return Err(Error::Cancelled);
+            }
+
+            buf.clear();
+            codec.encode(&value, &mut buf);
+            bytes += buf.len() as u64;
+            values.push(value);
+
+            if bytes > self.cap {
+                self.sort_values(&mut values, &key);
+                let path = self.run_path(&mut dir, runs.len())?;
+                let spilled = self.spill(values.iter().map(Ok), &codec, &path)?;
+                stats.records += values.len() as u64;
+                stats.runs += 1;
+                stats.bytes_spilled += spilled;
+                stats.peak_buffered_bytes = stats.peak_buffered_bytes.max(bytes);
+                runs.push(Some(Run::File(path)));
+                lens.push(bytes);
+                values = vec![];
+                bytes = 0;
+            }
+        }
+
+        if !values.is_empty() {
+            stats.records += values.len() as u64;
+            stats.runs += 1;
+            stats.peak_buffered_bytes = stats.peak_buffered_bytes.max(bytes);
+        }
+
+        self.sort_values(&mut values, &key);
+        runs.push(Some(Run::Memory(values)));
+        lens.push(bytes);
+        stats.run_duration = start.elapsed();
+
+        let order = Order {
+            desc: self.desc,
+            stable: true,
+            unique: self.unique,
+        };
+        let codec = Arc::new(codec);
+        let mut plan = merge::plan(&lens, self.max_fan_in);
+        // Merged as the iterator is consumed.
+        let last = plan.pop().unwrap_or_else(|| vec![0]);
+        let mut passes = vec![0; lens.len()];
+        let start = Instant::now();
+
+        for (i, inputs) in plan.into_iter().enumerate() {
+            let pass = inputs.iter().map(|&j| passes[j]).max().unwrap_or(0) + 1;
+            passes.push(pass);
+            let inputs = inputs
+                .into_iter()
+                .filter_map(|j| runs[j].take())
+                .collect::<Vec<Run<T>>>();
+            let paths = inputs
+                .iter()
+                .filter_map(|r| match r {
+                    Run::File(path) => Some(path.clone()),
+                    Run::Memory(_) => None,
+                })
+                .collect::<Vec<PathBuf>>();
+            let readers = inputs
+                .into_iter()
+                .map(|r| r.open(&codec))
+                .collect::<Result<Vec<RunReader<T, C>>>>()?;
+            let path = self.run_path(&mut dir, lens.len() + i)?;
+            let merger = Merger::new(readers, order, false, &key)?;
+            stats.bytes_spilled += self.spill(merger, codec.as_ref(), &path)?;
+            stats.merges += 1;
+            stats.merge_passes = stats.merge_passes.max(pass);
+
+            for p in paths {
+                file_utils::remove(&p).map_err(Error::TempSpace)?;
+            }
+
+            runs.push(Some(Run::File(path)));
+        }
+
+        if last.len() > 1 {
+            let pass = last.iter().map(|&j| passes[j]).max().unwrap_or(0) + 1;
+            stats.merges += 1;
+            stats.merge_passes = stats.merge_passes.max(pass);
+        }
+
+        stats.merge_duration = start.elapsed();
+
+        let inputs = last
+            .into_iter()
+            .filter_map(|j| runs[j].take().map(|r| r.open(&codec)))
+            .collect::<Result<Vec<RunReader<T, C>>>>()?;
+
+        Ok(SortedIter {
+            cancel: self.cancel.clone(),
+            merger: Merger::new(inputs, order, false, key)?,
+            stats,
+            _dir: dir,
+        })
+    }
+
+    /// Same as `sort_iter_by_key_with` for strings.
+    pub fn sort_iter_by_key<I, F, K>(
+        &self,
+        iter: I,
+        key: F,
+    ) -> Result<SortedIter<String, StringCodec, F, K>>
+    where
+        I: IntoIterator<Item = String>,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        self.sort_iter_by_key_with(iter, StringCodec, key)
+    }
+
+    fn sort_values<T, F, K>(&self, values: &mut [T], key: &F)
+    where
+        F: Fn(&T) -> K,
+        K: Ord,
+    {
+        let desc = self.desc;
+
+        slice_utils::sort_by_cached_key(values, key, |k1, _, k2, _| {
+            let ord = k1.cmp(k2);
+            if desc {
+                ord.reverse()
+            } else {
+                ord
+            }
+        });
+    }
+
+    // Path of run `n` in a temporary directory created with the first run.
+    fn run_path(&self, dir: &mut Option<tempfile::TempDir>, n: usize) -> Result<PathBuf> {
+        if dir.is_none() {
+            let created = match &self.tmp_dir {
+                Some(tmp) => tempfile::tempdir_in(tmp),
+                None => tempfile::tempdir(),
+            };
+
+            *dir = Some(created.map_err(Error::TempSpace)?);
+        }
+
+        let dir = dir.as_ref().map(|d| d.path()).unwrap_or(Path::new(""));
+        Ok(dir.join(format!("{}.run", n)))
+    }
+
+    // Write `values` to a run, returning the bytes written.
+    fn spill<T, C, V, I>(&self, values: I, codec: &C, path: &Path) -> Result<u64>
+    where
+        C: Codec<T>,
+        V: Borrow<T>,
+        I: IntoIterator<Item = Result<V>>,
+    {
+        let mut writer = io::BufWriter::new(file_utils::create(path).map_err(Error::TempSpace)?);
+        let mut buf = vec![];
+        let mut spilled = 0;
+
+        for value in values {
+            if self.cancel.as_ref().is_some_and(|t| t.is_cancelled()) {
+                return Err(Error::Cancelled);
+            }
+
+            buf.clear();
+            codec.encode(value?.borrow(), &mut buf);
+            writer.write_all(&buf).map_err(Error::TempSpace)?;
+            spilled += buf.len() as u64;
+        }
+
+        writer.flush().map_err(Error::TempSpace)?;
+        Ok(spilled)
+    }
+}
+
+pub fn sort_iter_by_key<I, F, K>(
+    iter: I,
+    cap: u64,
+    key: F,
+) -> Result<SortedIter<String, StringCodec, F, K>>
+where
+    I: IntoIterator<Item = String>,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new().capacity(cap).sort_iter_by_key(iter, key)
+}
+
+pub fn sort_iter_by_key_with<I, T, C, F, K>(
+    iter: I,
+    cap: u64,
+    codec: C,
+    key: F,
+) -> Result<SortedIter<T, C, F, K>>
+where
+    I: IntoIterator<Item = T>,
+    C: Codec<T>,
+    F: Fn(&T) -> K,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .sort_iter_by_key_with(iter, codec, key)
+}
@@ -0,0 +1,440 @@
+use super::chunk;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::Error;
+use super::Result;
+use super::Sorter;
+use std::cmp::Ordering;
+use std::fs;
+use std::io;
+use std::io::Seek;
+use std::io::Write;
+use std::slice;
+
+/// Which keys `join_by_key` returns groups for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinKind {
+    /// Keys found in both inputs.
+    Inner,
+    /// Keys found in the left input.
+    Left,
+    /// Keys found in the right input.
+    Right,
+    /// Keys found in either input.
+    Full,
+}
+
+impl JoinKind {
+    fn keeps(self, left: bool, right: bool) -> bool {
+        match self {
+            JoinKind::Inner => left && right,
+            JoinKind::Left => left,
+            JoinKind::Right => right,
+            JoinKind::Full => left || right,
+        }
+    }
+}
+
+/// The lines of one input with the key of a `JoinGroup`, in input order.
+///
+/// At most `capacity` bytes of them are held in memory, the rest is in a temporary file.
+pub struct JoinSide {
+    lines: Vec<String>,
+    spilled: Option<fs::File>,
+    len: usize,
+    delim: u8,
+}
+
+impl JoinSide {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Iterate over the lines. Each call starts from the first line, so that one side
+    /// can be iterated once for each line of the other. Iterators of the same side
+    /// share the temporary file and must not be interleaved.
+    pub fn iter(&self) -> JoinSideIter<'_> {
+        let mut error = None;
+        let mut spilled = None;
+
+        if let Some(mut f) = self.spilled.as_ref() {
+            match f.seek(io::SeekFrom::Start(0)) {
+                Ok(_) => {
+                    let reader = io::BufReader::new(f);
+                    spilled = Some(Records::new(reader, self.delim, Source::Temp));
+                }
+                Err(e) => error = Some(Error::TempSpace(e)),
+            }
+        }
+
+        JoinSideIter {
+            error,
+            spilled,
+            lines: self.lines.iter(),
+        }
+    }
+}
+
+/// Iterator returned by `JoinSide::iter`.
+pub struct JoinSideIter<'a> {
+    error: Option<Error>,
+    // Lines spilled to the temporary file, which come before `lines`.
+    spilled: Option<Records<io::BufReader<&'a fs::File>>>,
+    lines: slice::Iter<'a, String>,
+}
+
+impl<'a> Iterator for JoinSideIter<'a> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.lines = [].iter();
+            return Some(Err(e));
+        }
+
+        if let Some(records) = &mut self.spilled {
+            let mut line = String::new();
+
+            match records.read(&mut line) {
+                Ok(0) => self.spilled = None,
+                Ok(_) => return Some(Ok(line)),
+                Err(e) => {
+                    self.spilled = None;
+                    self.lines = [].iter();
+                    return Some(Err(e));
+                }
+            }
+        }
+
+        self.lines.next().cloned().map(Ok)
+    }
+}
+
+// Collects the lines of a `JoinSide`, spilling them once they exceed `capacity`.
+struct SideBuilder<'a> {
+    sorter: &'a Sorter,
+    side: JoinSide,
+    writer: Option<io::BufWriter<fs::File>>,
+    buffered: u64,
+}
+
+impl<'a> SideBuilder<'a> {
+    fn new(sorter: &'a Sorter) -> SideBuilder<'a> {
+        SideBuilder {
+            sorter,
+            side: JoinSide {
+                lines: vec![],
+                spilled: None,
+                len: 0,
+                delim: sorter.delim,
+            },
+            writer: None,
+            buffered: 0,
+        }
+    }
+
+    fn push(&mut self, line: String) -> Result<()> {
+        self.buffered += line.len() as u64;
+        self.side.lines.push(line);
+        self.side.len += 1;
+
+        if self.buffered > self.sorter.cap {
+            if self.writer.is_none() {
+                let f = self.sorter.tempfile().map_err(Error::TempSpace)?;
+                self.writer = Some(io::BufWriter::new(f));
+            }
+
+            if let Some(writer) = &mut self.writer {
+                for l in self.side.lines.drain(..) {
+                    writer.write_all(l.as_bytes()).map_err(Error::TempSpace)?;
+                }
+            }
+
+            self.buffered = 0;
+        }
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<JoinSide> {
+        if let Some(writer) = self.writer {
+            self.side.spilled = Some(chunk::rewind(writer)?);
+        }
+
+        Ok(self.side)
+    }
+}
+
+/// Lines of both inputs with the same key, returned by `join_by_key`.
+/// One of the sides is empty for keys found in only one input.
+pub struct JoinGroup<K> {
+    pub key: K,
+    pub left: JoinSide,
+    pub right: JoinSide,
+}
+
+// An input of a join and its next line.
+struct Input<R, K> {
+    records: Records<io::BufReader<R>>,
+    next: Option<(K, String)>,
+}
+
+/// Groups of lines with equal keys, in key order, returned by `join_by_key`.
+pub struct Join<R1, R2, F, K> {
+    sorter: Sorter,
+    kind: JoinKind,
+    left: Input<R1, K>,
+    right: Input<R2, K>,
+    key: F,
+}
+
+impl<R1, R2, F, K> Join<R1, R2, F, K>
+where
+    R1: io::Read,
+    R2: io::Read,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    fn new(sorter: &Sorter, left: R1, right: R2, kind: JoinKind, key: F) -> Result<Self> {
+        Ok(Join {
+            sorter: sorter.clone(),
+            kind,
+            left: Input::new(left, sorter.delim, &key)?,
+            right: Input::new(right, sorter.delim, &key)?,
+            key,
+        })
+    }
+
+    fn next_group(&mut self) -> Result<Option<JoinGroup<K>>> {
+        loop {
+            if self
+                .sorter
+                .cancel
+                .as_ref()
+                .is_some_and(|t| t.is_cancelled())
+            {
+                return Err(Error::Cancelled);
+            }
+
+            let ord = match (&self.left.next, &self.right.next) {
+                (None, None) => return Ok(None),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((k1, _)), Some((k2, _))) => self.cmp_keys(k1, k2),
+            };
+
+            let has_left = ord != Ordering::Greater;
+            let has_right = ord != Ordering::Less;
+
+            if !self.kind.keeps(has_left, has_right) {
+                // Skip the group without keeping its lines.
+                if has_left {
+                    self.skip_group(0)?;
+                } else {
+                    self.skip_group(1)?;
+                }
+
+                continue;
+            }
+
+            let (key, left) = if has_left {
+                self.read_group(0)?
+            } else {
+                (None, SideBuilder::new(&self.sorter).finish()?)
+            };
+
+            let (key, right) = if has_right {
+                let (k, right) = self.read_group(1)?;
+                (key.or(k), right)
+            } else {
+                (key, SideBuilder::new(&self.sorter).finish()?)
+            };
+
+            // Either side has at least one line.
+            let key = key.expect("a join group has a key");
+            return Ok(Some(JoinGroup { key, left, right }));
+        }
+    }
+
+    fn cmp_keys(&self, k1: &K, k2: &K) -> Ordering {
+        cmp_keys(self.sorter.desc, k1, k2)
+    }
+
+    // Read the lines of input `src` with the key of its next line.
+    fn read_group(&mut self, src: usize) -> Result<(Option<K>, JoinSide)> {
+        let mut builder = SideBuilder::new(&self.sorter);
+        let push = |line| builder.push(line);
+
+        let key = match src {
+            0 => self.left.take_group(0, &self.sorter, &self.key, push)?,
+            _ => self.right.take_group(1, &self.sorter, &self.key, push)?,
+        };
+
+        Ok((key, builder.finish()?))
+    }
+
+    fn skip_group(&mut self, src: usize) -> Result<()> {
+        match src {
+            0 => self
+                .left
+                .take_group(0, &self.sorter, &self.key, |_| Ok(()))?,
+            _ => self
+                .right
+                .take_group(1, &self.sorter, &self.key, |_| Ok(()))?,
+        };
+
+        Ok(())
+    }
+}
+
+impl<R, K> Input<R, K>
+where
+    R: io::Read,
+    K: Ord,
+{
+    fn new<F>(reader: R, delim: u8, key: &F) -> Result<Input<R, K>>
+    where
+        F: Fn(&String) -> K,
+    {
+        let mut input = Input {
+            records: Records::new(io::BufReader::new(reader), delim, Source::Input),
+            next: None,
+        };
+
+        input.advance(key)?;
+        Ok(input)
+    }
+
+    fn advance<F>(&mut self, key: &F) -> Result<()>
+    where
+        F: Fn(&String) -> K,
+    {
+        let mut line = String::new();
+
+        self.next = match self.records.read(&mut line)? {
+            0 => None,
+            _ => Some((key(&line), line)),
+        };
+
+        Ok(())
+    }
+
+    // Pass the lines with the key of the next line to `push` and return that key.
+    // `src` is the position of the input for `Error::NotSorted`.
+    fn take_group<F, P>(
+        &mut self,
+        src: usize,
+        sorter: &Sorter,
+        key: &F,
+        mut push: P,
+    ) -> Result<Option<K>>
+    where
+        F: Fn(&String) -> K,
+        P: FnMut(String) -> Result<()>,
+    {
+        let (k, line) = match self.next.take() {
+            Some(next) => next,
+            None => return Ok(None),
+        };
+
+        push(line)?;
+        self.advance(key)?;
+
+        while let Some((next_key, _)) = &self.next {
+            if *next_key != k {
+                if sorter.verify && cmp_keys(sorter.desc, &k, next_key) == Ordering::Greater {
+                    return Err(Error::NotSorted {
+                        input: src,
+                        line_num: self.records.line_num(),
+                    });
+                }
+
+                break;
+            }
+
+            if let Some((_, line)) = self.next.take() {
+                push(line)?;
+            }
+
+            self.advance(key)?;
+        }
+
+        Ok(Some(k))
+    }
+}
+
+fn cmp_keys<K: Ord>(desc: bool, k1: &K, k2: &K) -> Ordering {
+    if desc {
+        k2.cmp(k1)
+    } else {
+        k1.cmp(k2)
+    }
+}
+
+impl<R1, R2, F, K> Iterator for Join<R1, R2, F, K>
+where
+    R1: io::Read,
+    R2: io::Read,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    type Item = Result<JoinGroup<K>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_group().transpose()
+    }
+}
+
+impl Sorter {
+    /// Join two inputs that are already sorted by `key`, in the order set by `reverse`.
+    ///
+    /// Each group holds the lines of both inputs with one key. At most `capacity` bytes
+    /// of each side of a group are held in memory, so groups may be larger than memory.
+    pub fn join_by_key<R1, R2, F, K>(
+        &self,
+        left: R1,
+        right: R2,
+        kind: JoinKind,
+        key: F,
+    ) -> Result<Join<R1, R2, F, K>>
+    where
+        R1: io::Read,
+        R2: io::Read,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        Join::new(self, left, right, kind, key)
+    }
+
+    /// Same as `join_by_key`, sorting both inputs to temporary files first.
+    pub fn sort_and_join_by_key<F, K>(
+        &self,
+        left: fs::File,
+        right: fs::File,
+        kind: JoinKind,
+        key: F,
+    ) -> Result<Join<fs::File, fs::File, F, K>>
+    where
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let left = self.sort_to_tempfile(left, &key)?;
+        let right = self.sort_to_tempfile(right, &key)?;
+        Join::new(self, left, right, kind, key)
+    }
+
+    fn sort_to_tempfile<F, K>(&self, fin: fs::File, key: &F) -> Result<fs::File>
+    where
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let f = self.tempfile().map_err(Error::TempSpace)?;
+        let mut writer = io::BufWriter::new(f);
+        self.sort_by_key(fin, &mut writer, key)?;
+        chunk::rewind(writer)
+    }
+}
@@ -22,22 +22,488 @@
 //!     ex_merge_sort_by_key::sort_by_key(f, io::stdout(), capacity, |line| line.len()).unwrap();
 //! }
 //! ```
+//!
+//! ### Merge sorted files
+//!
+//! ```rust,no_run
+//! use std::fs::File;
+//! use std::io;
+//!
+//! fn main() {
+//!     let inputs = vec![File::open("a.txt").unwrap(), File::open("b.txt").unwrap()];
+//!     ex_merge_sort_by_key::merge_by_key(inputs, io::stdout(), |line| line.len()).unwrap();
+//! }
+//! ```
 
 #[cfg(test)]
 mod tests;
 
+mod argsort;
+#[cfg(feature = "async")]
+mod async_sort;
+mod cancel;
+mod check;
 mod chunk;
+mod context;
+mod error;
 mod file_utils;
+mod group_by;
+mod iter_sort;
+mod join;
+mod merge;
+mod natural;
+mod order;
+mod partition;
+mod progress;
+mod range;
+mod replacement;
+mod set_op;
 mod slice_utils;
+mod sorted_file;
+mod sstable;
+mod stats;
+mod top_k;
+mod try_sort;
+mod work_dir;
 
+pub use argsort::{argsort_by_key, Position};
+#[cfg(feature = "async")]
+pub use async_sort::{reverse_sort_by_key_async, sort_by_key_async};
+pub use cancel::{is_cancelled, CancelToken};
+pub use check::Disorder;
 use chunk::Chunk;
+use context::Context;
+use context::Filter;
+pub use error::{Error, Result};
+use file_utils::Records;
 use file_utils::RoughCount;
-use io::prelude::BufRead;
+use file_utils::Source;
+pub use group_by::{Group, Groups};
+pub use iter_sort::{sort_iter_by_key, sort_iter_by_key_with, Codec, SortedIter, StringCodec};
+pub use join::{Join, JoinGroup, JoinKind, JoinSide, JoinSideIter};
+use merge::Merger;
+pub use natural::NaturalRuns;
+use order::Order;
+pub use partition::PartitionBy;
+use progress::Observer;
+pub use progress::Progress;
+pub use range::Ranges;
+use range::Sampler;
+pub use set_op::SetOp;
+pub use sorted_file::{SortedFile, SortedRange};
+pub use sstable::{SsTable, SsTableOptions, SsTableRange};
+pub use stats::SortStats;
+use std::cell::RefCell;
 use std::fs;
 use std::io;
 use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use top_k::TopK;
+pub use try_sort::{try_sort_by_key, OnKeyError};
+use work_dir::WorkDir;
+
+pub const DEFAULT_CAPACITY: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_FAN_IN: usize = 64;
+
+/// Sort and merge settings shared by the functions of this crate.
+///
+/// ```rust
+/// use std::fs::File;
+/// use std::io;
+/// use ex_merge_sort_by_key::Sorter;
+///
+/// let f = File::open("README.md").unwrap();
+/// Sorter::new().capacity(1024).reverse(true).sort_by_key(f, io::stdout(), |line| line.len()).unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct Sorter {
+    cap: u64,
+    desc: bool,
+    stable: bool,
+    unique: bool,
+    verify: bool,
+    delim: u8,
+    tmp_dir: Option<PathBuf>,
+    observer: Option<Observer>,
+    cancel: Option<CancelToken>,
+    work_dir: Option<PathBuf>,
+    replacement_selection: bool,
+    max_fan_in: usize,
+    natural_runs: NaturalRuns,
+    #[cfg(feature = "mmap")]
+    mmap: bool,
+}
+
+impl Default for Sorter {
+    fn default() -> Self {
+        Sorter {
+            cap: DEFAULT_CAPACITY,
+            desc: false,
+            stable: true,
+            unique: false,
+            verify: false,
+            delim: b'\n',
+            tmp_dir: None,
+            observer: None,
+            cancel: None,
+            work_dir: None,
+            replacement_selection: false,
+            max_fan_in: DEFAULT_MAX_FAN_IN,
+            natural_runs: NaturalRuns::Off,
+            #[cfg(feature = "mmap")]
+            mmap: false,
+        }
+    }
+}
+
+impl Sorter {
+    pub fn new() -> Sorter {
+        Sorter::default()
+    }
+
+    /// Maximum number of bytes sorted in memory at once.
+    pub fn capacity(mut self, cap: u64) -> Sorter {
+        self.cap = cap;
+        self
+    }
+
+    /// Sort in descending order.
+    pub fn reverse(mut self, desc: bool) -> Sorter {
+        self.desc = desc;
+        self
+    }
+
+    /// Keep lines with equal keys in input order (default).
+    /// Otherwise they are ordered by comparing the whole lines, as sort(1) does without `-s`.
+    pub fn stable(mut self, stable: bool) -> Sorter {
+        self.stable = stable;
+        self
+    }
+
+    /// Output only the first of the lines with equal keys.
+    /// Lines are then compared by key only, as if `stable` were set.
+    pub fn unique(mut self, unique: bool) -> Sorter {
+        self.unique = unique;
+        self
+    }
+
+    /// Make `merge_by_key` and `join_by_key` fail with `Error::NotSorted`
+    /// when an input is not sorted.
+    pub fn verify(mut self, verify: bool) -> Sorter {
+        self.verify = verify;
+        self
+    }
+
+    /// Byte that terminates each line. Use `b'\0'` for NUL-terminated records.
+    /// A last line without it is terminated in the output, as sort(1) does.
+    pub fn delimiter(mut self, delim: u8) -> Sorter {
+        self.delim = delim;
+        self
+    }
+
+    /// Generate runs of input that does not fit in `capacity` by replacement selection
+    /// instead of splitting it in halves. Runs are then about twice the capacity, or a single
+    /// run for sorted input, so there are fewer merges. A resumed sort generates them again.
+    pub fn replacement_selection(mut self, replacement_selection: bool) -> Sorter {
+        self.replacement_selection = replacement_selection;
+        self
+    }
+
+    /// Maximum number of runs merged at once, each of which is an open file.
+    /// More runs are merged in several passes, planned to rewrite as few bytes as possible.
+    /// Values below 2 are taken as 2.
+    pub fn max_fan_in(mut self, max_fan_in: usize) -> Sorter {
+        self.max_fan_in = max_fan_in;
+        self
+    }
+
+    /// Turn stretches of input that are already sorted, or reverse sorted with
+    /// `NaturalRuns::SortedOrReversed`, into runs as they are read, and copy sorted input
+    /// to the output without merging. Sorted input is then read twice, or three times when
+    /// it turns out not to be. Takes precedence over `replacement_selection`.
+    pub fn natural_runs(mut self, natural_runs: NaturalRuns) -> Sorter {
+        self.natural_runs = natural_runs;
+        self
+    }
+
+    /// Generate runs of `capacity` bytes through a memory map of the input, sorting the
+    /// positions of the lines so that each line is copied only when its run is written.
+    /// The input must not be modified during the sort. Takes precedence over
+    /// `replacement_selection` and the runs of `natural_runs`.
+    #[cfg(feature = "mmap")]
+    pub fn mmap(mut self, mmap: bool) -> Sorter {
+        self.mmap = mmap;
+        self
+    }
+
+    /// Directory for temporary files instead of `std::env::temp_dir()`.
+    pub fn temp_dir<P: AsRef<Path>>(mut self, dir: P) -> Sorter {
+        self.tmp_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Call `f` whenever a run is produced, a merge starts or output is written.
+    pub fn progress<F>(mut self, f: F) -> Sorter
+    where
+        F: Fn(&Progress) + Send + Sync + 'static,
+    {
+        self.observer = Some(Observer(Arc::new(f)));
+        self
+    }
+
+    /// Abort with `Error::Cancelled` once `token` is cancelled.
+    /// Temporary files are removed before returning, except those kept in `work_dir`.
+    pub fn cancel_token(mut self, token: CancelToken) -> Sorter {
+        self.cancel = Some(token);
+        self
+    }
+
+    /// Keep the runs of `sort_by_key` in `dir` together with a manifest of the completed steps.
+    /// If the sort fails or is cancelled, calling it again with the same input, settings and key
+    /// resumes from the last completed step. The input is recognized by its length, modification
+    /// time and first and last blocks. The files of the sort are removed from `dir` when it completes.
+    pub fn work_dir<P: AsRef<Path>>(mut self, dir: P) -> Sorter {
+        self.work_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn sort_by_key<T, F, K>(&self, fin: fs::File, fout: T, key: F) -> Result<SortStats>
+    where
+        T: io::Write,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        self.sort_then(fin, &key, None, |sorted, ctx| {
+            file_utils::copy(sorted, ctx.writer(fout), self.delim)
+        })
+    }
+
+    /// Merge inputs that are already sorted by `key` without sorting them again.
+    /// Lines with equal keys are written in the order of `inputs`, or ordered by the whole
+    /// lines without `stable`.
+    pub fn merge_by_key<R, T, F, K>(&self, inputs: Vec<R>, fout: T, key: F) -> Result<SortStats>
+    where
+        R: io::Read,
+        T: io::Write,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let start = Instant::now();
+        let ctx = Context::new(self, 0);
+        let n = inputs.len() as u64;
+        let readers = inputs
+            .into_iter()
+            .map(|r| Records::new(io::BufReader::new(r), self.delim, Source::Input))
+            .collect();
+        let merger = Merger::new(readers, self.order(), self.verify, &key)?;
+        let mut writer = io::BufWriter::new(ctx.writer(fout));
+
+        ctx.update(|p| {
+            p.runs = n;
+            p.merge_pass = 1;
+        });
+
+        let mut records = 0;
+
+        for line in merger {
+            ctx.check_cancelled()?;
+            let line = line?;
+            ctx.update_quietly(|p| p.bytes_read += line.len() as u64);
+            writer.write_all(line.as_bytes()).map_err(Error::write)?;
+            records += 1;
+        }
+
+        writer.flush().map_err(Error::write)?;
+        drop(writer);
+
+        ctx.stats(|s| {
+            s.records = records;
+            s.runs = n;
+            s.merges = 1;
+            s.merge_passes = 1;
+            s.merge_duration = start.elapsed();
+        });
 
-pub fn sort_by_key<T, F, K>(fin: fs::File, fout: T, cap: u64, key: F) -> io::Result<()>
+        Ok(ctx.into_stats())
+    }
+
+    /// Find the first line that is out of order without writing anything.
+    /// With `unique`, lines with equal keys are out of order too.
+    pub fn check_sorted_by_key<R, F, K>(&self, fin: R, key: F) -> Result<Option<Disorder>>
+    where
+        R: io::Read,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        check::check_sorted(fin, &self.order(), self.delim, &key)
+    }
+
+    pub fn is_sorted_by_key<R, F, K>(&self, fin: R, key: F) -> Result<bool>
+    where
+        R: io::Read,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        Ok(self.check_sorted_by_key(fin, key)?.is_none())
+    }
+
+    /// Write the first `k` lines in sorted order.
+    /// Temporary files are used only when those lines do not fit in `capacity`.
+    pub fn top_k_by_key<R, T, F, K>(&self, fin: R, fout: T, k: usize, key: F) -> Result<SortStats>
+    where
+        R: io::Read,
+        T: io::Write,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let ctx = Context::new(self, 0);
+        let mut records = Records::new(io::BufReader::new(fin), self.delim, Source::Input);
+        let mut writer = io::BufWriter::new(ctx.writer(fout));
+
+        match top_k::top_k(&mut records, k, &ctx, &key)? {
+            TopK::InMemory(lines) => {
+                for l in lines {
+                    writer.write_all(l.as_bytes()).map_err(Error::write)?;
+                }
+            }
+            TopK::Overflow(lines) => {
+                let f = self.tempfile().map_err(Error::TempSpace)?;
+                let mut tmp = io::BufWriter::new(f);
+                let mut spilled = 0;
+                let mut buf = String::new();
+
+                for l in lines {
+                    tmp.write_all(l.as_bytes()).map_err(Error::TempSpace)?;
+                    spilled += l.len() as u64;
+                }
+
+                while records.read(&mut buf)? > 0 {
+                    ctx.check_cancelled()?;
+                    tmp.write_all(buf.as_bytes()).map_err(Error::TempSpace)?;
+                    spilled += buf.len() as u64;
+                    buf.clear();
+                }
+
+                let f = chunk::rewind(tmp)?;
+                ctx.stats(|s| s.bytes_spilled += spilled);
+
+                let sorted = sort_chunk(Chunk::new(f, &ctx, "r".to_string())?, &key)?;
+                let start = Instant::now();
+                file_utils::copy_head(&sorted.file, &mut writer, k as u64, self.delim)?;
+                ctx.stats(|s| s.output_duration += start.elapsed());
+            }
+        }
+
+        writer.flush().map_err(Error::write)?;
+        drop(writer);
+        Ok(ctx.into_stats())
+    }
+
+    // Sort `fin` and pass the sorted temporary file to `output`.
+    // `sampler` collects lines while runs are generated, see `Context::samples`.
+    fn sort_then<F, K, O>(
+        &self,
+        fin: fs::File,
+        key: &F,
+        sampler: Option<Sampler>,
+        output: O,
+    ) -> Result<SortStats>
+    where
+        F: Fn(&String) -> K,
+        K: Ord,
+        O: FnOnce(&fs::File, &Context) -> Result<()>,
+    {
+        self.sort_filtered_then(fin, key, sampler, None, output)
+    }
+
+    // Same as `sort_then`, leaving out the lines of `fin` that `filter` rejects.
+    fn sort_filtered_then<'a, F, K, O>(
+        &'a self,
+        fin: fs::File,
+        key: &F,
+        sampler: Option<Sampler>,
+        filter: Option<Filter<'a>>,
+        output: O,
+    ) -> Result<SortStats>
+    where
+        F: Fn(&String) -> K,
+        K: Ord,
+        O: FnOnce(&fs::File, &Context) -> Result<()>,
+    {
+        let mut ctx = self.context(&fin, sampler, filter)?;
+        let chunk = Chunk::input(fin, &ctx)?;
+        let sorted = sort_chunk(chunk, key)?;
+
+        let start = Instant::now();
+        output(&sorted.file, &ctx)?;
+        ctx.stats(|s| s.output_duration += start.elapsed());
+        drop(sorted);
+
+        if let Some(wd) = ctx.work_dir.take() {
+            wd.finish().map_err(Error::TempSpace)?;
+        }
+
+        Ok(ctx.into_stats())
+    }
+
+    // Context of a sort of `fin`, opening `work_dir` if set.
+    fn context<'a>(
+        &'a self,
+        fin: &fs::File,
+        sampler: Option<Sampler>,
+        filter: Option<Filter<'a>>,
+    ) -> Result<Context<'a>> {
+        let len = fin.metadata().map_err(Error::Read)?.len();
+        let mut ctx = Context::new(self, len);
+        ctx.sampler = sampler.map(RefCell::new);
+        ctx.filter = filter;
+
+        if let Some(dir) = &self.work_dir {
+            let input = work_dir::fingerprint(fin).map_err(Error::Read)?;
+            let samples = ctx.sampler.as_ref().map_or(0, |s| s.borrow().max);
+            let settings = self.settings(&input, samples);
+            let wd = WorkDir::open(dir, &settings).map_err(Error::TempSpace)?;
+            ctx.work_dir = Some(wd);
+        }
+
+        Ok(ctx)
+    }
+
+    fn tempfile(&self) -> io::Result<fs::File> {
+        file_utils::tempfile(self.tmp_dir.as_deref())
+    }
+
+    // Settings that a resumed sort must share with the interrupted one, the fingerprint
+    // of its input and the number of lines sampled by `sort_by_key_ranges`.
+    fn settings(&self, input: &str, samples: usize) -> String {
+        format!(
+            "capacity={} reverse={} stable={} unique={} delimiter={} replacement_selection={} max_fan_in={} natural_runs={:?} samples={} input={}",
+            self.cap,
+            self.desc,
+            self.stable,
+            self.unique,
+            self.delim,
+            self.replacement_selection,
+            self.max_fan_in,
+            self.natural_runs,
+            samples,
+            input
+        )
+    }
+
+    fn order(&self) -> Order {
+        Order {
+            desc: self.desc,
+            stable: self.stable,
+            unique: self.unique,
+        }
+    }
+}
+
+pub fn sort_by_key<T, F, K>(fin: fs::File, fout: T, cap: u64, key: F) -> Result<SortStats>
 where
     T: io::Write,
     F: Fn(&String) -> K,
@@ -46,7 +512,7 @@ where
     sort_by_key_with_order(fin, fout, cap, false, key)
 }
 
-pub fn reverse_sort_by_key<T, F, K>(fin: fs::File, fout: T, cap: u64, key: F) -> io::Result<()>
+pub fn reverse_sort_by_key<T, F, K>(fin: fs::File, fout: T, cap: u64, key: F) -> Result<SortStats>
 where
     T: io::Write,
     F: Fn(&String) -> K,
@@ -61,95 +527,414 @@ pub fn sort_by_key_with_order<T, F, K>(
     cap: u64,
     desc: bool,
     key: F,
-) -> io::Result<()>
+) -> Result<SortStats>
+where
+    T: io::Write,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .reverse(desc)
+        .sort_by_key(fin, fout, key)
+}
+
+pub fn merge_by_key<R, T, F, K>(inputs: Vec<R>, fout: T, key: F) -> Result<SortStats>
+where
+    R: io::Read,
+    T: io::Write,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new().merge_by_key(inputs, fout, key)
+}
+
+pub fn reverse_merge_by_key<R, T, F, K>(inputs: Vec<R>, fout: T, key: F) -> Result<SortStats>
 where
+    R: io::Read,
     T: io::Write,
     F: Fn(&String) -> K,
     K: Ord,
 {
-    let chunk = Chunk::new(fin, cap)?;
-    let sorted = sort_chunk(chunk, desc, &key)?;
-    file_utils::copy(&sorted.file, fout)
+    Sorter::new().reverse(true).merge_by_key(inputs, fout, key)
 }
 
-fn sort_chunk<F, K>(chunk: Chunk, desc: bool, key: &F) -> io::Result<Chunk>
+pub fn top_k_by_key<R, T, F, K>(fin: R, fout: T, cap: u64, k: usize, key: F) -> Result<SortStats>
 where
+    R: io::Read,
+    T: io::Write,
     F: Fn(&String) -> K,
     K: Ord,
 {
-    if chunk.rough_count == RoughCount::Zero || chunk.rough_count == RoughCount::One {
-        return Ok(chunk);
+    Sorter::new().capacity(cap).top_k_by_key(fin, fout, k, key)
+}
+
+pub fn reverse_top_k_by_key<R, T, F, K>(
+    fin: R,
+    fout: T,
+    cap: u64,
+    k: usize,
+    key: F,
+) -> Result<SortStats>
+where
+    R: io::Read,
+    T: io::Write,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .reverse(true)
+        .top_k_by_key(fin, fout, k, key)
+}
+
+pub fn check_sorted_by_key<R, F, K>(fin: R, key: F) -> Result<Option<Disorder>>
+where
+    R: io::Read,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new().check_sorted_by_key(fin, key)
+}
+
+pub fn is_sorted_by_key<R, F, K>(fin: R, key: F) -> Result<bool>
+where
+    R: io::Read,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    Sorter::new().is_sorted_by_key(fin, key)
+}
+
+// A sorted run waiting to be merged, closed so that the number of open files
+// is bounded by `max_fan_in` however many runs there are.
+struct Run {
+    name: String,
+    len: u64,
+}
+
+impl Run {
+    fn new(name: String, chunk: Chunk) -> Result<Run> {
+        Ok(Run {
+            name,
+            len: chunk.len()?,
+        })
+    }
+}
+
+// A chunk that is sorted as it is, or the runs left for the last merge.
+enum Sorted<'a> {
+    Chunk(Chunk<'a>),
+    Runs(LastMerge),
+}
+
+// Runs of the last merge of `merge::plan`, with the id and pass of that merge.
+// A single run needs no merge.
+struct LastMerge {
+    runs: Vec<Run>,
+    id: String,
+    pass: u64,
+}
+
+fn sort_chunk<'a, F, K>(chunk: Chunk<'a>, key: &F) -> Result<Chunk<'a>>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let ctx = chunk.ctx;
+
+    let mut last = match sort_runs(chunk, key)? {
+        Sorted::Chunk(chunk) => return Ok(chunk),
+        Sorted::Runs(last) => last,
+    };
+
+    let run = match last.runs.len() {
+        1 => last.runs.remove(0),
+        _ => merge(ctx, last.runs, &last.id, last.pass, key)?,
+    };
+
+    Chunk::new(ctx.open_file(&run.name)?, ctx, "r".to_string())
+}
+
+// Sort `chunk` up to the last merge, which is left to the caller.
+fn sort_runs<'a, F, K>(chunk: Chunk<'a>, key: &F) -> Result<Sorted<'a>>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let ctx = chunk.ctx;
+
+    if chunk.rough_count == RoughCount::Zero {
+        return Ok(Sorted::Chunk(chunk));
+    }
+
+    // The input is copied to the output as it is, unless some lines may be left out.
+    let filtered = ctx.filter.is_some();
+
+    if chunk.rough_count == RoughCount::One && !filtered {
+        chunk.sample()?;
+        let len = chunk.len()?;
+        ctx.run_produced(1, len, len);
+        return Ok(Sorted::Chunk(chunk));
+    }
+
+    if let Some(runs) = ctx.work_dir.as_ref().and_then(|wd| wd.runs()) {
+        ctx.resume_samples()?;
+        let runs = runs
+            .into_iter()
+            .map(|(name, len)| Run { name, len })
+            .collect();
+
+        return merge_runs(ctx, runs, key).map(Sorted::Runs);
     }
 
-    if chunk.fit_in_buffer() {
-        return chunk.sort(desc, key);
+    if ctx.sorter.natural_runs != NaturalRuns::Off && !filtered && natural::is_sorted(&chunk, key)?
+    {
+        return Ok(Sorted::Chunk(chunk));
+    }
+
+    let mut runs = vec![];
+    generate_runs(chunk, key, &mut runs)?;
+
+    let list = runs
+        .iter()
+        .map(|r| format!(" {}:{}", r.name, r.len))
+        .collect::<String>();
+    ctx.commit_samples()?;
+    ctx.commit(&[], &format!("runs{}", list))?;
+
+    merge_runs(ctx, runs, key).map(Sorted::Runs)
+}
+
+// Sort `chunk` into runs that fit in `capacity`, in input order.
+fn generate_runs<F, K>(chunk: Chunk, key: &F, runs: &mut Vec<Run>) -> Result<()>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let ctx = chunk.ctx;
+    let id = chunk.id.clone();
+
+    if let Some(wd) = &ctx.work_dir {
+        if resume_runs(ctx, wd, &id, key, runs)? {
+            return Ok(());
+        }
+    }
+
+    if chunk.rough_count == RoughCount::Zero {
+        drop(chunk);
+        return ctx.remove_file(&format!("{}.in", id));
+    }
+
+    #[cfg(feature = "mmap")]
+    if ctx.sorter.mmap {
+        let sorted = chunk.mapped_runs(key)?;
+        drop(chunk);
+
+        for run in sorted {
+            runs.push(Run::new(format!("{}.run", run.id), run)?);
+        }
+
+        return ctx.remove_file(&format!("{}.in", id));
+    }
+
+    // A single line longer than `capacity` is a run too.
+    if chunk.rough_count == RoughCount::One || chunk.fit_in_buffer()? {
+        let sorted = chunk.sort(key)?;
+        drop(chunk);
+        runs.push(Run::new(format!("{}.run", id), sorted)?);
+        return ctx.remove_file(&format!("{}.in", id));
+    }
+
+    if ctx.sorter.natural_runs != NaturalRuns::Off {
+        let sorted = natural::runs(&chunk, key)?;
+        drop(chunk);
+
+        for run in sorted {
+            runs.push(Run::new(format!("{}.run", run.id), run)?);
+        }
+
+        return ctx.remove_file(&format!("{}.in", id));
+    }
+
+    if ctx.sorter.replacement_selection {
+        let sorted = replacement::runs(&chunk, key)?;
+        drop(chunk);
+
+        for run in sorted {
+            runs.push(Run::new(format!("{}.run", run.id), run)?);
+        }
+
+        return ctx.remove_file(&format!("{}.in", id));
     }
 
     let (c1, c2) = chunk.split()?;
+    drop(chunk);
+    ctx.remove_file(&format!("{}.in", id))?;
+    generate_runs(c1, key, runs)?;
+    generate_runs(c2, key, runs)
+}
 
-    if c2.rough_count == RoughCount::Zero {
-        return c1.sort(desc, key);
+// Add the runs of the chunk `id` that an interrupted sort completed.
+// Returns false when the chunk still has to be sorted or split.
+fn resume_runs<F, K>(
+    ctx: &Context,
+    wd: &WorkDir,
+    id: &str,
+    key: &F,
+    runs: &mut Vec<Run>,
+) -> Result<bool>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    if let Some(f) = wd.sorted(id).map_err(Error::TempSpace)? {
+        let len = f.metadata().map_err(Error::TempSpace)?.len();
+
+        // The run was sampled by the interrupted sort, whose samples are lost.
+        if ctx.sampler.is_some() {
+            Chunk::new(f, ctx, id.to_string())?.sample()?;
+        }
+
+        runs.push(Run {
+            name: format!("{}.run", id),
+            len,
+        });
+        return Ok(true);
+    }
+
+    if !wd.is_split(id) {
+        return Ok(false);
     }
 
-    Ok(merge(
-        sort_chunk(c1, desc, key)?,
-        sort_chunk(c2, desc, key)?,
-        desc,
-        key,
-    )?)
+    for half in &["0", "1"] {
+        let half_id = format!("{}{}", id, half);
+
+        if !resume_runs(ctx, wd, &half_id, key, runs)? {
+            let f = ctx.open_file(&format!("{}.in", half_id))?;
+            generate_runs(Chunk::new(f, ctx, half_id)?, key, runs)?;
+        }
+    }
+
+    Ok(true)
 }
 
-fn merge<F, K>(c1: Chunk, c2: Chunk, desc: bool, key: &F) -> io::Result<Chunk>
+// Merge `runs` following `merge::plan` up to the last merge, skipping the merges of
+// an interrupted sort that completed.
+fn merge_runs<F, K>(ctx: &Context, runs: Vec<Run>, key: &F) -> Result<LastMerge>
 where
     F: Fn(&String) -> K,
     K: Ord,
 {
-    assert!(c1.capacity == c2.capacity);
+    let lens = runs.iter().map(|r| r.len).collect::<Vec<u64>>();
+    let mut passes = vec![0; runs.len()];
+    let mut runs = runs.into_iter().map(Some).collect::<Vec<Option<Run>>>();
+    let mut plan = merge::plan(&lens, ctx.sorter.max_fan_in);
+    let last = plan.pop().unwrap_or_else(|| (0..runs.len()).collect());
 
-    let mut reader1 = io::BufReader::new(&c1.file);
-    let mut reader2 = io::BufReader::new(&c2.file);
-    let mut writer = io::BufWriter::new(tempfile::tempfile()?);
-    let mut r1_buf = String::new();
-    let mut r2_buf = String::new();
+    for (i, inputs) in plan.into_iter().enumerate() {
+        let id = format!("m{}", i);
+        let pass = inputs.iter().map(|&j| passes[j]).max().unwrap_or(0) + 1;
+        let inputs = inputs
+            .into_iter()
+            .filter_map(|j| runs[j].take())
+            .collect::<Vec<Run>>();
+        passes.push(pass);
 
-    let mut r1_read = reader1.read_line(&mut r1_buf)?;
-    let mut r2_read = reader2.read_line(&mut r2_buf)?;
+        let merged = match &ctx.work_dir {
+            Some(wd) if wd.is_sorted(&id) => Run {
+                name: format!("{}.run", id),
+                len: 0,
+            },
+            _ => merge(ctx, inputs, &id, pass, key)?,
+        };
 
-    let mut r1_key = key(&r1_buf);
-    let mut r2_key = key(&r2_buf);
+        runs.push(Some(merged));
+    }
 
-    while r1_read > 0 && r2_read > 0 {
-        if (!desc && r1_key < r2_key) || (desc && r1_key > r2_key) {
-            writer.write(&r1_buf.as_bytes())?;
-            r1_buf.clear();
-            r1_read = reader1.read_line(&mut r1_buf)?;
+    let id = format!("m{}", passes.len() - lens.len());
+    let pass = last.iter().map(|&j| passes[j]).max().unwrap_or(0) + 1;
+    let inputs = last
+        .into_iter()
+        .filter_map(|j| runs[j].take())
+        .collect::<Vec<Run>>();
 
-            if r1_read > 0 {
-                r1_key = key(&r1_buf);
-            }
-        } else {
-            writer.write(&r2_buf.as_bytes())?;
-            r2_buf.clear();
-            r2_read = reader2.read_line(&mut r2_buf)?;
+    let left = runs.iter().flatten().count();
 
-            if r2_read > 0 {
-                r2_key = key(&r2_buf);
-            }
+    if left > 0 {
+        return Err(Error::TempSpace(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("merges left {} runs out of the last merge", left),
+        )));
+    }
+
+    if let Some(wd) = &ctx.work_dir {
+        if wd.is_sorted(&id) {
+            let run = Run {
+                name: format!("{}.run", id),
+                len: 0,
+            };
+
+            return Ok(LastMerge {
+                runs: vec![run],
+                id,
+                pass,
+            });
         }
     }
 
-    while r1_read > 0 {
-        writer.write(&r1_buf.as_bytes())?;
-        r1_buf.clear();
-        r1_read = reader1.read_line(&mut r1_buf)?;
+    Ok(LastMerge {
+        runs: inputs,
+        id,
+        pass,
+    })
+}
+
+fn merge<F, K>(ctx: &Context, inputs: Vec<Run>, id: &str, pass: u64, key: &F) -> Result<Run>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let start = Instant::now();
+    let sorter = ctx.sorter;
+    ctx.update(|p| p.merge_pass = pass);
+
+    let files = inputs
+        .iter()
+        .map(|r| ctx.open_file(&r.name))
+        .collect::<Result<Vec<fs::File>>>()?;
+    let readers = files
+        .iter()
+        .map(|f| Records::new(io::BufReader::new(f), sorter.delim, Source::Temp))
+        .collect();
+    let merger = Merger::new(readers, sorter.order(), false, key)?;
+    let name = format!("{}.run", id);
+    let mut writer = io::BufWriter::new(ctx.create_file(&name)?);
+    let mut spilled = 0;
+
+    for line in merger {
+        ctx.check_cancelled()?;
+        let line = line?;
+        writer
+            .write_all(line.as_bytes())
+            .map_err(Error::TempSpace)?;
+        spilled += line.len() as u64;
     }
 
-    while r2_read > 0 {
-        writer.write(&r2_buf.as_bytes())?;
-        r2_buf.clear();
-        r2_read = reader2.read_line(&mut r2_buf)?;
+    let f = chunk::rewind(writer)?;
+    ctx.commit(&[&f], &format!("merge {} {}", id, pass))?;
+    drop(files);
+
+    for run in &inputs {
+        ctx.remove_file(&run.name)?;
     }
 
-    let cap = c1.capacity;
-    Ok(Chunk::new(writer.into_inner()?, cap)?)
+    ctx.stats(|s| {
+        s.merges += 1;
+        s.merge_passes = s.merge_passes.max(pass);
+        s.bytes_spilled += spilled;
+        s.merge_duration += start.elapsed();
+    });
+
+    Ok(Run { name, len: spilled })
 }
@@ -0,0 +1,219 @@
+use super::error::Error;
+use super::error::Result;
+use super::file_utils::Records;
+use super::order::Order;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::io::BufRead;
+
+// A sorted input of a merge.
+pub(crate) trait Input {
+    type Value;
+
+    fn next(&mut self) -> Result<Option<Self::Value>>;
+
+    // Number of values read so far, for `Error::NotSorted`.
+    fn line_num(&self) -> u64;
+
+    // What values with equal keys are compared by, see `Order::cmp`.
+    // Only lines are compared, other values keep their input order.
+    fn line(_value: &Self::Value) -> &str {
+        ""
+    }
+}
+
+impl<R: BufRead> Input for Records<R> {
+    type Value = String;
+
+    fn next(&mut self) -> Result<Option<String>> {
+        let mut line = String::new();
+
+        match self.read(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
+        }
+    }
+
+    fn line_num(&self) -> u64 {
+        Records::line_num(self)
+    }
+
+    fn line(value: &String) -> &str {
+        value
+    }
+}
+
+struct Head<I: Input, K> {
+    key: K,
+    src: usize,
+    value: I::Value,
+    order: Order,
+}
+
+impl<I: Input, K: Ord> Ord for Head<I, K> {
+    // BinaryHeap pops the greatest element, so the head that has to be written
+    // first compares as the greatest. Ties go to the input that comes first.
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order
+            .cmp(
+                &other.key,
+                I::line(&other.value),
+                &self.key,
+                I::line(&self.value),
+            )
+            .then_with(|| other.src.cmp(&self.src))
+    }
+}
+
+impl<I: Input, K: Ord> PartialOrd for Head<I, K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<I: Input, K: Ord> PartialEq for Head<I, K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<I: Input, K: Ord> Eq for Head<I, K> {}
+
+pub(crate) struct Merger<I: Input, F, K> {
+    inputs: Vec<I>,
+    heap: BinaryHeap<Head<I, K>>,
+    last: Option<K>,
+    key: F,
+    order: Order,
+    verify: bool,
+}
+
+impl<I, F, K> Merger<I, F, K>
+where
+    I: Input,
+    F: Fn(&I::Value) -> K,
+    K: Ord,
+{
+    pub(crate) fn new(inputs: Vec<I>, order: Order, verify: bool, key: F) -> Result<Self> {
+        let n = inputs.len();
+
+        let mut merger = Merger {
+            inputs,
+            heap: BinaryHeap::with_capacity(n),
+            last: None,
+            key,
+            order,
+            verify,
+        };
+
+        for src in 0..n {
+            merger.fill(src, None)?;
+        }
+
+        Ok(merger)
+    }
+
+    // Key of a value returned by the merger.
+    pub(crate) fn key(&self, value: &I::Value) -> K {
+        (self.key)(value)
+    }
+
+    fn fill(&mut self, src: usize, prev: Option<&Head<I, K>>) -> Result<()> {
+        let value = match self.inputs[src].next()? {
+            Some(value) => value,
+            None => return Ok(()),
+        };
+
+        let key = (self.key)(&value);
+
+        // Equal keys are sorted even with `unique`, which drops them from the output.
+        if let Some(prev) = prev {
+            if self.verify
+                && self
+                    .order
+                    .cmp(&prev.key, I::line(&prev.value), &key, I::line(&value))
+                    == Ordering::Greater
+            {
+                return Err(Error::NotSorted {
+                    input: src,
+                    line_num: self.inputs[src].line_num(),
+                });
+            }
+        }
+
+        self.heap.push(Head {
+            key,
+            src,
+            value,
+            order: self.order,
+        });
+
+        Ok(())
+    }
+}
+
+impl<I, F, K> Iterator for Merger<I, F, K>
+where
+    I: Input,
+    F: Fn(&I::Value) -> K,
+    K: Ord,
+{
+    type Item = Result<I::Value>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let head = self.heap.pop()?;
+
+            if let Err(e) = self.fill(head.src, Some(&head)) {
+                return Some(Err(e));
+            }
+
+            if let Some(last) = &self.last {
+                if self.order.is_dup(last, &head.key) {
+                    continue;
+                }
+            }
+
+            if self.order.unique {
+                self.last = Some(head.key);
+            }
+
+            return Some(Ok(head.value));
+        }
+    }
+}
+
+// Merges that reduce runs of the given lengths to one, at most `fan_in` runs at a time.
+// Runs are numbered in input order and the output of merge `i` is run `lens.len() + i`.
+//
+// Each merge takes adjacent runs, so that lines with equal keys keep their input order,
+// choosing those with the fewest bytes. The first merge takes just enough runs for every
+// later merge to be full, since the bytes it writes are rewritten by later passes.
+pub(crate) fn plan(lens: &[u64], fan_in: usize) -> Vec<Vec<usize>> {
+    let fan_in = fan_in.max(2);
+    let mut runs = lens
+        .iter()
+        .copied()
+        .enumerate()
+        .collect::<Vec<(usize, u64)>>();
+    let mut steps = vec![];
+
+    while runs.len() > 1 {
+        let n = runs.len();
+
+        let k = if steps.is_empty() && n > fan_in {
+            (n - 2) % (fan_in - 1) + 2
+        } else {
+            n.min(fan_in)
+        };
+
+        let bytes = |i: usize| runs[i..i + k].iter().map(|(_, len)| len).sum::<u64>();
+        let start = (0..=n - k).min_by_key(|&i| bytes(i)).unwrap_or(0);
+        let merged = (lens.len() + steps.len(), bytes(start));
+        let inputs = runs.splice(start..start + k, [merged]).map(|(i, _)| i);
+
+        steps.push(inputs.collect());
+    }
+
+    steps
+}
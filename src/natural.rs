@@ -0,0 +1,235 @@
+use super::chunk::Chunk;
+use super::chunk::RunWriter;
+use super::context::Context;
+use super::order::Order;
+use super::Result;
+use std::cmp::Ordering;
+
+/// Stretches of presorted input that `Sorter::natural_runs` turns into runs without sorting.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NaturalRuns {
+    /// Sort every run in memory (default).
+    Off,
+    /// Lines already in sort order.
+    Sorted,
+    /// Lines already in sort order, or in strictly reverse order which are reversed.
+    SortedOrReversed,
+}
+
+// A run being written, dropping lines with the key of the last one under `unique`.
+struct Run<'a, K> {
+    writer: RunWriter<'a>,
+    order: Order,
+    last: Option<K>,
+}
+
+impl<'a, K: Ord> Run<'a, K> {
+    fn new(ctx: &'a Context<'a>, id: String) -> Result<Run<'a, K>> {
+        Ok(Run {
+            writer: RunWriter::new(ctx, id)?,
+            order: ctx.sorter.order(),
+            last: None,
+        })
+    }
+
+    fn write(&mut self, k: K, line: String) -> Result<()> {
+        self.writer.count(line.len());
+
+        if let Some(last) = &self.last {
+            if self.order.is_dup(last, &k) {
+                return Ok(());
+            }
+        }
+
+        self.writer.write(line.as_bytes())?;
+
+        if self.order.unique {
+            self.last = Some(k);
+        }
+
+        Ok(())
+    }
+
+    fn finish(self, peak: u64) -> Result<Chunk<'a>> {
+        self.writer.finish(peak)
+    }
+}
+
+// Whether `chunk` is already sorted, in which case it is counted as a single run.
+pub(super) fn is_sorted<F, K>(chunk: &Chunk, key: &F) -> Result<bool>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let ctx = chunk.ctx;
+    let order = ctx.sorter.order();
+    let mut records = chunk.records();
+    let mut prev: Option<(K, String)> = None;
+    let mut buf = String::new();
+    let mut sorted = true;
+    // Only the last two lines are held in memory.
+    let mut peak = 0;
+
+    while records.read(&mut buf)? > 0 {
+        ctx.check_cancelled()?;
+        let k = key(&buf);
+
+        if let Some((pk, pl)) = &prev {
+            peak = peak.max((pl.len() + buf.len()) as u64);
+
+            if !order.in_order(pk, pl, &k, &buf) {
+                sorted = false;
+                break;
+            }
+        }
+
+        prev = Some((k, std::mem::take(&mut buf)));
+    }
+
+    let (count, bytes) = (records.line_num(), records.offset());
+    drop(records);
+    chunk.rewind()?;
+
+    if sorted {
+        chunk.sample()?;
+        ctx.run_produced(count, bytes, peak);
+    }
+
+    Ok(sorted)
+}
+
+// Sorted runs of `chunk` that follow its presorted stretches.
+//
+// Lines are buffered up to `capacity` bytes while the stretch they belong to is tracked.
+// A stretch in sort order that outgrows the buffer is written as it is read until it ends,
+// so that sorted input of any length is a single run. A reversed stretch is written
+// reversed when it fills the buffer. Otherwise the lines before the current stretch are
+// sorted and written as a run, like `Chunk::sort` does.
+pub(super) fn runs<'a, F, K>(chunk: &Chunk<'a>, key: &F) -> Result<Vec<Chunk<'a>>>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let ctx = chunk.ctx;
+    let order = ctx.sorter.order();
+    let cap = ctx.sorter.cap;
+    let reversible = ctx.sorter.natural_runs == NaturalRuns::SortedOrReversed;
+    let mut records = chunk.records();
+    let mut runs = vec![];
+    let mut lines: Vec<(K, String)> = vec![];
+    let mut bytes = 0;
+    let mut peak = 0;
+    // `lines[stretch..]` are in sort order, or in strictly reverse order if `reversed`.
+    let mut stretch = 0;
+    let mut reversed = false;
+    // A stretch in sort order longer than the buffer, and its last line.
+    let mut stream: Option<(Run<K>, K, String)> = None;
+    let mut buf = String::new();
+
+    let new_run = |runs: &Vec<Chunk>| Run::new(ctx, format!("{}.n{}", chunk.id, runs.len()));
+
+    while records.read(&mut buf)? > 0 {
+        ctx.check_cancelled()?;
+        ctx.sample(&buf);
+        let k = key(&buf);
+        let line = std::mem::take(&mut buf);
+
+        if let Some((mut run, lk, ll)) = stream.take() {
+            if order.cmp(&lk, &ll, &k, &line) != Ordering::Greater {
+                run.write(lk, ll)?;
+                stream = Some((run, k, line));
+                continue;
+            }
+
+            run.write(lk, ll)?;
+            runs.push(run.finish(peak)?);
+            stretch = 0;
+            reversed = false;
+        } else if let Some((lk, ll)) = lines.last() {
+            let ord = order.cmp(lk, ll, &k, &line);
+
+            if lines.len() - stretch == 1 && reversible && ord == Ordering::Greater {
+                reversed = true;
+            } else if reversed != (ord == Ordering::Greater) {
+                stretch = lines.len();
+                reversed = false;
+            }
+        }
+
+        bytes += line.len() as u64;
+        peak = peak.max(bytes);
+        lines.push((k, line));
+
+        if bytes <= cap {
+            continue;
+        }
+
+        if stretch > 0 {
+            let rest = lines.split_off(stretch);
+            runs.push(write_sorted(new_run(&runs)?, lines, peak)?);
+            lines = rest;
+            bytes = lines.iter().map(|(_, l)| l.len() as u64).sum();
+            stretch = 0;
+        } else if reversed {
+            lines.reverse();
+            runs.push(write_lines(new_run(&runs)?, lines, peak)?);
+            lines = vec![];
+            bytes = 0;
+            reversed = false;
+        } else if let Some((lk, ll)) = lines.pop() {
+            let mut run = new_run(&runs)?;
+
+            for (k, l) in lines {
+                run.write(k, l)?;
+            }
+
+            stream = Some((run, lk, ll));
+            lines = vec![];
+            bytes = 0;
+        }
+    }
+
+    if let Some((mut run, lk, ll)) = stream {
+        run.write(lk, ll)?;
+        runs.push(run.finish(peak)?);
+    }
+
+    if !lines.is_empty() {
+        let run = new_run(&runs)?;
+
+        let run = match (stretch, reversed) {
+            (0, false) => write_lines(run, lines, peak)?,
+            (0, true) => {
+                lines.reverse();
+                write_lines(run, lines, peak)?
+            }
+            _ => write_sorted(run, lines, peak)?,
+        };
+
+        runs.push(run);
+    }
+
+    Ok(runs)
+}
+
+fn write_sorted<'a, K: Ord>(
+    run: Run<'a, K>,
+    mut lines: Vec<(K, String)>,
+    peak: u64,
+) -> Result<Chunk<'a>> {
+    let order = run.order;
+    lines.sort_by(|(k1, l1), (k2, l2)| order.cmp(k1, l1, k2, l2));
+    write_lines(run, lines, peak)
+}
+
+fn write_lines<'a, K: Ord>(
+    mut run: Run<'a, K>,
+    lines: Vec<(K, String)>,
+    peak: u64,
+) -> Result<Chunk<'a>> {
+    for (k, l) in lines {
+        run.write(k, l)?;
+    }
+
+    run.finish(peak)
+}
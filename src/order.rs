@@ -0,0 +1,34 @@
+use std::cmp::Ordering;
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Order {
+    pub(crate) desc: bool,
+    pub(crate) stable: bool,
+    pub(crate) unique: bool,
+}
+
+impl Order {
+    // Ordering of two lines in the output. `Ordering::Equal` means the lines
+    // keep their input order.
+    pub(crate) fn cmp<K: Ord>(&self, k1: &K, l1: &str, k2: &K, l2: &str) -> Ordering {
+        let mut ord = k1.cmp(k2);
+
+        if !self.stable && !self.unique {
+            ord = ord.then_with(|| l1.cmp(l2));
+        }
+
+        if self.desc {
+            ord.reverse()
+        } else {
+            ord
+        }
+    }
+
+    pub(crate) fn is_dup<K: Ord>(&self, k1: &K, k2: &K) -> bool {
+        self.unique && k1 == k2
+    }
+
+    pub(crate) fn in_order<K: Ord>(&self, k1: &K, l1: &str, k2: &K, l2: &str) -> bool {
+        self.cmp(k1, l1, k2, l2) != Ordering::Greater && !self.is_dup(k1, k2)
+    }
+}
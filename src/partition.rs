@@ -0,0 +1,146 @@
+use super::context::Context;
+use super::context::ProgressWriter;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::fs;
+use std::io;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Limit {
+    Bytes(u64),
+    Records(u64),
+}
+
+/// When `sort_by_key_partitioned` moves on to the next output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PartitionBy {
+    limit: Limit,
+    keep_groups: bool,
+}
+
+impl PartitionBy {
+    /// Start a new output before a line that would take the current one over `n` bytes.
+    /// An output holds at least one line, even one longer than `n`.
+    pub fn bytes(n: u64) -> PartitionBy {
+        PartitionBy {
+            limit: Limit::Bytes(n),
+            keep_groups: false,
+        }
+    }
+
+    /// Start a new output after every `n` lines.
+    pub fn records(n: u64) -> PartitionBy {
+        PartitionBy {
+            limit: Limit::Records(n),
+            keep_groups: false,
+        }
+    }
+
+    /// Keep lines with equal keys in the same output, even if it goes over the limit.
+    pub fn keep_groups(mut self, keep_groups: bool) -> PartitionBy {
+        self.keep_groups = keep_groups;
+        self
+    }
+
+    fn is_full(&self, bytes: u64, records: u64, next_len: u64) -> bool {
+        match self.limit {
+            Limit::Bytes(n) => bytes + next_len > n,
+            Limit::Records(n) => records >= n,
+        }
+    }
+}
+
+impl Sorter {
+    /// Same as `sort_by_key`, writing the sorted lines to a sequence of outputs instead of one.
+    ///
+    /// `new_output` is called with 0, 1, 2, ... whenever a new output is needed.
+    /// No output is created for an empty input.
+    pub fn sort_by_key_partitioned<T, P, F, K>(
+        &self,
+        fin: fs::File,
+        by: PartitionBy,
+        new_output: P,
+        key: F,
+    ) -> Result<SortStats>
+    where
+        T: io::Write,
+        P: FnMut(usize) -> io::Result<T>,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let mut partitions = 0;
+
+        let mut stats = self.sort_then(fin, &key, None, |sorted, ctx| {
+            partitions = copy_partitioned(sorted, ctx, by, new_output, &key)?;
+            Ok(())
+        })?;
+
+        stats.partitions = partitions;
+        Ok(stats)
+    }
+}
+
+// Returns the number of outputs written.
+fn copy_partitioned<T, P, F, K>(
+    fin: &fs::File,
+    ctx: &Context,
+    by: PartitionBy,
+    mut new_output: P,
+    key: &F,
+) -> Result<u64>
+where
+    T: io::Write,
+    P: FnMut(usize) -> io::Result<T>,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let mut records = Records::new(io::BufReader::new(fin), ctx.sorter.delim, Source::Temp);
+    let mut writer: Option<io::BufWriter<ProgressWriter<T>>> = None;
+    let mut partitions = 0;
+    let mut bytes = 0;
+    let mut count = 0;
+    let mut prev = None;
+    let mut buf = String::new();
+
+    while records.read(&mut buf)? > 0 {
+        let len = buf.len() as u64;
+        let k = if by.keep_groups {
+            Some(key(&buf))
+        } else {
+            None
+        };
+        let in_group = by.keep_groups && prev.is_some() && prev == k;
+
+        if writer.is_none() || (!in_group && by.is_full(bytes, count, len)) {
+            if let Some(mut w) = writer.take() {
+                w.flush().map_err(Error::write)?;
+            }
+
+            let out = new_output(partitions as usize).map_err(Error::Write)?;
+            writer = Some(io::BufWriter::new(ctx.writer(out)));
+            partitions += 1;
+            bytes = 0;
+            count = 0;
+        }
+
+        if let Some(w) = &mut writer {
+            w.write_all(buf.as_bytes()).map_err(Error::write)?;
+        }
+
+        bytes += len;
+        count += 1;
+        prev = k;
+        buf.clear();
+    }
+
+    if let Some(mut w) = writer {
+        w.flush().map_err(Error::write)?;
+    }
+
+    Ok(partitions)
+}
@@ -0,0 +1,26 @@
+use std::fmt;
+use std::sync::Arc;
+
+/// Snapshot passed to the callback set by `Sorter::progress`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Size of the input, or 0 when it is unknown.
+    pub input_bytes: u64,
+    /// Bytes of the input that have been sorted into runs (or merged by `merge_by_key`).
+    pub bytes_read: u64,
+    /// Sorted runs produced so far.
+    pub runs: u64,
+    /// Merge pass in progress. Pass 1 merges runs, pass 2 merges the results of pass 1 and so on.
+    pub merge_pass: u64,
+    /// Bytes written to the output.
+    pub bytes_written: u64,
+}
+
+#[derive(Clone)]
+pub(crate) struct Observer(pub(crate) Arc<dyn Fn(&Progress) + Send + Sync>);
+
+impl fmt::Debug for Observer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Observer")
+    }
+}
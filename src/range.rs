@@ -0,0 +1,194 @@
+use super::context::Context;
+use super::context::ProgressWriter;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::fs;
+use std::io;
+use std::io::Write;
+
+// Lines sampled per requested range to pick the splitters.
+const SAMPLES_PER_RANGE: usize = 100;
+
+// Keeps every `stride`-th line, doubling `stride` whenever `max` lines are kept,
+// so that the kept lines are spread evenly over the input.
+pub(crate) struct Sampler {
+    pub(crate) max: usize,
+    stride: u64,
+    seen: u64,
+    pub(crate) lines: Vec<String>,
+}
+
+impl Sampler {
+    fn new(max: usize) -> Sampler {
+        Sampler {
+            max: max.max(2),
+            stride: 1,
+            seen: 0,
+            lines: vec![],
+        }
+    }
+
+    pub(crate) fn add(&mut self, line: &str) {
+        if self.seen.is_multiple_of(self.stride) {
+            if self.lines.len() >= self.max {
+                let mut i = 0;
+
+                self.lines.retain(|_| {
+                    i += 1;
+                    i % 2 == 1
+                });
+
+                self.stride *= 2;
+            }
+
+            if self.seen.is_multiple_of(self.stride) {
+                self.lines.push(line.to_string());
+            }
+        }
+
+        self.seen += 1;
+    }
+}
+
+/// Result of `sort_by_key_ranges`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ranges<K> {
+    /// Keys that start each output but the first, in sort order.
+    /// Output `i` holds the lines with keys from `splitters[i - 1]` up to, but not including,
+    /// `splitters[i]`.
+    pub splitters: Vec<K>,
+    pub stats: SortStats,
+}
+
+impl Sorter {
+    /// Same as `sort_by_key`, writing the sorted lines to `n` outputs of roughly equal size
+    /// split by key range.
+    ///
+    /// Keys are sampled while runs are generated to pick `n - 1` splitters. There are fewer
+    /// when the samples do not have enough distinct keys. `new_output` is called with
+    /// 0, 1, 2, ... once for each range, so an output may be empty.
+    pub fn sort_by_key_ranges<T, P, F, K>(
+        &self,
+        fin: fs::File,
+        n: usize,
+        new_output: P,
+        key: F,
+    ) -> Result<Ranges<K>>
+    where
+        T: io::Write,
+        P: FnMut(usize) -> io::Result<T>,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let sampler = Sampler::new(n.saturating_mul(SAMPLES_PER_RANGE));
+        let mut splitters = vec![];
+
+        let stats = self.sort_then(fin, &key, Some(sampler), |sorted, ctx| {
+            splitters = pick_splitters(ctx, n, &key);
+            copy_ranges(sorted, ctx, &splitters, new_output, &key)
+        })?;
+
+        Ok(Ranges { splitters, stats })
+    }
+}
+
+fn pick_splitters<F, K>(ctx: &Context, n: usize, key: &F) -> Vec<K>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let desc = ctx.sorter.desc;
+    let mut keys = ctx.samples().iter().map(key).collect::<Vec<K>>();
+
+    keys.sort_by(|a, b| if desc { b.cmp(a) } else { a.cmp(b) });
+
+    // The samples at these indices start a range.
+    let len = keys.len();
+    let mut starts = (1..n).map(|i| i * len / n).filter(|&j| j > 0).peekable();
+    let mut splitters: Vec<K> = vec![];
+    let mut first = None;
+
+    for (j, k) in keys.into_iter().enumerate() {
+        if j == 0 {
+            first = Some(k);
+            continue;
+        }
+
+        if starts.peek() != Some(&j) {
+            continue;
+        }
+
+        while starts.peek() == Some(&j) {
+            starts.next();
+        }
+
+        // A splitter equal to the previous one, or to the first key, would leave a range empty.
+        if splitters.last().or(first.as_ref()) != Some(&k) {
+            splitters.push(k);
+        }
+    }
+
+    splitters
+}
+
+fn copy_ranges<T, P, F, K>(
+    fin: &fs::File,
+    ctx: &Context,
+    splitters: &[K],
+    mut new_output: P,
+    key: &F,
+) -> Result<()>
+where
+    T: io::Write,
+    P: FnMut(usize) -> io::Result<T>,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let desc = ctx.sorter.desc;
+    let reached = |k: &K, s: &K| if desc { k <= s } else { k >= s };
+    let mut records = Records::new(io::BufReader::new(fin), ctx.sorter.delim, Source::Temp);
+    let mut writer = next_output(ctx, &mut new_output, 0)?;
+    let mut range = 0;
+    let mut buf = String::new();
+
+    while records.read(&mut buf)? > 0 {
+        if range < splitters.len() {
+            let k = key(&buf);
+
+            while range < splitters.len() && reached(&k, &splitters[range]) {
+                writer.flush().map_err(Error::write)?;
+                range += 1;
+                writer = next_output(ctx, &mut new_output, range)?;
+            }
+        }
+
+        writer.write_all(buf.as_bytes()).map_err(Error::write)?;
+        buf.clear();
+    }
+
+    writer.flush().map_err(Error::write)?;
+
+    for i in range + 1..=splitters.len() {
+        let mut w = next_output(ctx, &mut new_output, i)?;
+        w.flush().map_err(Error::write)?;
+    }
+
+    Ok(())
+}
+
+fn next_output<'a, T, P>(
+    ctx: &'a Context<'a>,
+    new_output: &mut P,
+    i: usize,
+) -> Result<io::BufWriter<ProgressWriter<'a, T>>>
+where
+    T: io::Write,
+    P: FnMut(usize) -> io::Result<T>,
+{
+    let out = new_output(i).map_err(Error::Write)?;
+    Ok(io::BufWriter::new(ctx.writer(out)))
+}
@@ -0,0 +1,169 @@
+use super::chunk::Chunk;
+use super::chunk::RunWriter;
+use super::context::Context;
+use super::order::Order;
+use super::Result;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+struct Entry<K> {
+    run: u64,
+    key: K,
+    line: String,
+    seq: u64,
+    order: Order,
+}
+
+impl<K: Ord> Ord for Entry<K> {
+    // BinaryHeap pops the greatest element, so the entry that has to be written
+    // first compares as the greatest: lowest run, then sort order, then input order.
+    fn cmp(&self, other: &Self) -> Ordering {
+        other
+            .run
+            .cmp(&self.run)
+            .then_with(|| {
+                self.order
+                    .cmp(&other.key, &other.line, &self.key, &self.line)
+            })
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+impl<K: Ord> PartialOrd for Entry<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> PartialEq for Entry<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for Entry<K> {}
+
+// Writes the lines popped from the heap, starting a new run whenever the run of the
+// popped line changes.
+struct Runs<'a, K> {
+    ctx: &'a Context<'a>,
+    id: String,
+    order: Order,
+    current: Option<(u64, RunWriter<'a>)>,
+    last: Option<(K, String)>,
+    peak: u64,
+    done: Vec<Chunk<'a>>,
+}
+
+impl<'a, K: Ord> Runs<'a, K> {
+    fn write(&mut self, entry: Entry<K>) -> Result<()> {
+        if self.current.as_ref().map(|(n, _)| *n) != Some(entry.run) {
+            self.finish()?;
+            let id = format!("{}.{}", self.id, entry.run);
+            self.current = Some((entry.run, RunWriter::new(self.ctx, id)?));
+        }
+
+        let (_, run) = match &mut self.current {
+            Some(current) => current,
+            // Started above.
+            None => return Ok(()),
+        };
+
+        run.count(entry.line.len());
+
+        if let Some((k, _)) = &self.last {
+            if self.order.is_dup(k, &entry.key) {
+                return Ok(());
+            }
+        }
+
+        run.write(entry.line.as_bytes())?;
+        self.last = Some((entry.key, entry.line));
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some((_, run)) = self.current.take() {
+            self.last = None;
+            self.done.push(run.finish(self.peak)?);
+        }
+
+        Ok(())
+    }
+}
+
+// Sorted runs of `chunk` produced by replacement selection: lines go through a heap
+// of `capacity` bytes, and a line that sorts before the last line written starts the
+// next run. Runs are about twice the capacity on random input and a single run on
+// sorted input. Equal keys keep their input order across runs, so that merging
+// adjacent runs keeps the sort stable.
+pub(super) fn runs<'a, F, K>(chunk: &Chunk<'a>, key: &F) -> Result<Vec<Chunk<'a>>>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let ctx = chunk.ctx;
+    let order = ctx.sorter.order();
+    let cap = ctx.sorter.cap;
+    let mut records = chunk.records();
+    let mut heap = BinaryHeap::new();
+    let mut buffered = 0;
+    let mut seq = 0;
+    let mut buf = String::new();
+
+    let mut runs = Runs {
+        ctx,
+        id: chunk.id.clone(),
+        order,
+        current: None,
+        last: None,
+        peak: 0,
+        done: vec![],
+    };
+
+    while records.read(&mut buf)? > 0 {
+        ctx.check_cancelled()?;
+        ctx.sample(&buf);
+        let k = key(&buf);
+
+        // A line that sorts before the last one written waits for the next run.
+        let run = match (&runs.current, &runs.last) {
+            (Some((n, _)), Some((lk, ll))) if order.cmp(lk, ll, &k, &buf) == Ordering::Greater => {
+                n + 1
+            }
+            (Some((n, _)), _) => *n,
+            (None, _) => 0,
+        };
+
+        buffered += buf.len() as u64;
+        runs.peak = runs.peak.max(buffered);
+
+        heap.push(Entry {
+            run,
+            key: k,
+            line: std::mem::take(&mut buf),
+            seq,
+            order,
+        });
+
+        seq += 1;
+
+        while buffered > cap {
+            match heap.pop() {
+                Some(entry) => {
+                    buffered -= entry.line.len() as u64;
+                    runs.write(entry)?;
+                }
+                None => break,
+            }
+        }
+    }
+
+    while let Some(entry) = heap.pop() {
+        ctx.check_cancelled()?;
+        runs.write(entry)?;
+    }
+
+    runs.finish()?;
+    Ok(runs.done)
+}
@@ -0,0 +1,127 @@
+use super::context::Context;
+use super::Error;
+use super::JoinKind;
+use super::JoinSide;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::io;
+use std::io::Write;
+use std::time::Instant;
+
+/// Operation of `set_op_by_key`, like comm(1) on keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetOp {
+    /// Keys found in either input.
+    Union,
+    /// Keys found in both inputs.
+    Intersection,
+    /// Keys of the left input not found in the right one.
+    Difference,
+    /// Keys found in exactly one of the inputs.
+    SymmetricDifference,
+}
+
+impl SetOp {
+    fn join_kind(self) -> JoinKind {
+        match self {
+            SetOp::Union | SetOp::SymmetricDifference => JoinKind::Full,
+            SetOp::Intersection => JoinKind::Inner,
+            SetOp::Difference => JoinKind::Left,
+        }
+    }
+
+    // Lines to skip and to write from each input, given the number of lines with
+    // the same key in the left input `m` and in the right input `n`.
+    fn counts(self, m: usize, n: usize) -> ((usize, usize), (usize, usize)) {
+        match self {
+            SetOp::Union => ((0, m), (m, n.saturating_sub(m))),
+            SetOp::Intersection => ((0, m.min(n)), (0, 0)),
+            SetOp::Difference => ((n, m.saturating_sub(n)), (0, 0)),
+            SetOp::SymmetricDifference => ((n, m.saturating_sub(n)), (m, n.saturating_sub(m))),
+        }
+    }
+}
+
+impl Sorter {
+    /// Write the lines of two inputs already sorted by `key` that `op` keeps.
+    ///
+    /// Lines with equal keys are matched one to one as in comm(1), so that the inputs are
+    /// multisets: a key found `m` times on the left and `n` times on the right is written
+    /// `max(m, n)` times by `Union`, `min(m, n)` by `Intersection` and `m - n` by `Difference`.
+    /// With `unique`, the inputs are sets and each key is written at most once.
+    /// Lines of the left input are written before those of the right one.
+    pub fn set_op_by_key<R1, R2, T, F, K>(
+        &self,
+        left: R1,
+        right: R2,
+        fout: T,
+        op: SetOp,
+        key: F,
+    ) -> Result<SortStats>
+    where
+        R1: io::Read,
+        R2: io::Read,
+        T: io::Write,
+        F: Fn(&String) -> K,
+        K: Ord,
+    {
+        let start = Instant::now();
+        let ctx = Context::new(self, 0);
+        let join = self.join_by_key(left, right, op.join_kind(), key)?;
+        let mut writer = io::BufWriter::new(ctx.writer(fout));
+
+        ctx.update(|p| {
+            p.runs = 2;
+            p.merge_pass = 1;
+        });
+
+        let mut records = 0;
+
+        for group in join {
+            let group = group?;
+            let (mut m, mut n) = (group.left.len(), group.right.len());
+
+            if self.unique {
+                m = m.min(1);
+                n = n.min(1);
+            }
+
+            let (l, r) = op.counts(m, n);
+            records += write_lines(&mut writer, &group.left, l)?;
+            records += write_lines(&mut writer, &group.right, r)?;
+        }
+
+        writer.flush().map_err(Error::write)?;
+        drop(writer);
+
+        ctx.stats(|s| {
+            s.records = records;
+            s.runs = 2;
+            s.merges = 1;
+            s.merge_passes = 1;
+            s.merge_duration = start.elapsed();
+        });
+
+        Ok(ctx.into_stats())
+    }
+}
+
+// Write lines `skip..skip + take` of a side, returning how many were written.
+fn write_lines<W>(writer: &mut W, side: &JoinSide, (skip, take): (usize, usize)) -> Result<u64>
+where
+    W: io::Write,
+{
+    if take == 0 {
+        return Ok(0);
+    }
+
+    let mut written = 0;
+
+    for line in side.iter().skip(skip).take(take) {
+        writer.write_all(line?.as_bytes()).map_err(Error::write)?;
+        written += 1;
+    }
+
+    Ok(written)
+}
@@ -1,10 +1,12 @@
+use std::cmp::Ordering;
 use std::mem;
 
 // NOTE: copy from https://doc.rust-lang.org/std/primitive.slice.html#method.sort_by_cached_key
-pub(crate) fn sort_by_cached_key<T, K, F>(list: &mut Vec<T>, desc: bool, f: F)
+// NOTE: elements that compare equal keep their original order
+pub(crate) fn sort_by_cached_key<T, K, F, C>(list: &mut [T], f: F, compare: C)
 where
     F: FnMut(&T) -> K,
-    K: Ord,
+    C: Fn(&K, &T, &K, &T) -> Ordering,
 {
     macro_rules! sort_by_key {
         ($t:ty, $slice:ident, $f:ident) => {{
@@ -15,11 +17,10 @@ where
                 .map(|(i, k)| (k, i as $t))
                 .collect();
 
-            if desc {
-                indices.sort_unstable_by(|a, b| b.cmp(a));
-            } else {
-                indices.sort_unstable_by(|a, b| a.cmp(b));
-            }
+            indices.sort_unstable_by(|a, b| {
+                compare(&a.0, &$slice[a.1 as usize], &b.0, &$slice[b.1 as usize])
+                    .then(a.1.cmp(&b.1))
+            });
 
             for i in 0..$slice.len() {
                 let mut index = indices[i].1;
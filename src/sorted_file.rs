@@ -0,0 +1,204 @@
+use super::file_utils::ReadAt;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::Error;
+use super::Result;
+use std::fs;
+use std::io;
+use std::io::BufRead;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+/// A text file sorted by `key`, searched by binary search on byte offsets.
+///
+/// The file must be sorted as `sort_by_key` sorts it with the same key function,
+/// delimiter and order. Lookups read O(log n) lines. Each lookup and range reads from
+/// its own position, so they can be interleaved.
+pub struct SortedFile<F> {
+    file: fs::File,
+    len: u64,
+    delim: u8,
+    desc: bool,
+    key: F,
+}
+
+impl<F, K> SortedFile<F>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    pub fn open(file: fs::File, key: F) -> Result<SortedFile<F>> {
+        let len = file.metadata().map_err(Error::Read)?.len();
+
+        Ok(SortedFile {
+            file,
+            len,
+            delim: b'\n',
+            desc: false,
+            key,
+        })
+    }
+
+    /// Byte that terminates each line, as given to `Sorter::delimiter`.
+    pub fn delimiter(mut self, delim: u8) -> SortedFile<F> {
+        self.delim = delim;
+        self
+    }
+
+    /// The file is in descending order.
+    pub fn reverse(mut self, desc: bool) -> SortedFile<F> {
+        self.desc = desc;
+        self
+    }
+
+    /// The first line whose key is not before `k` in the order of the file.
+    pub fn lower_bound(&self, k: &K) -> Result<Option<String>> {
+        self.range((Bound::Included(k), Bound::Unbounded))
+            .next()
+            .transpose()
+    }
+
+    /// Lines with keys in `range`, in file order. `range` is in key order,
+    /// so with `reverse` it is `(Bound::Included(high), Bound::Included(low))`.
+    pub fn range<R>(&self, range: R) -> SortedRange<'_, F, R>
+    where
+        R: RangeBounds<K>,
+    {
+        SortedRange {
+            file: self,
+            range,
+            records: None,
+            done: false,
+        }
+    }
+
+    fn before(&self, k1: &K, k2: &K) -> bool {
+        if self.desc {
+            k1 > k2
+        } else {
+            k1 < k2
+        }
+    }
+
+    fn reader_at(&self, offset: u64) -> io::BufReader<ReadAt<'_>> {
+        io::BufReader::new(ReadAt::new(&self.file, offset))
+    }
+
+    // Offset of the first line at or after `offset`.
+    fn line_start(&self, offset: u64) -> Result<u64> {
+        if offset == 0 {
+            return Ok(0);
+        }
+
+        let mut reader = self.reader_at(offset - 1);
+        let mut skipped = vec![];
+        let n = reader
+            .read_until(self.delim, &mut skipped)
+            .map_err(Error::Read)?;
+
+        Ok(offset - 1 + n as u64)
+    }
+
+    // Offset of the first line for which `is_before` is false. `is_before` must be
+    // true for a prefix of the lines of the file and false for the rest.
+    fn partition_point<P>(&self, is_before: P) -> Result<u64>
+    where
+        P: Fn(&K) -> bool,
+    {
+        // Lines starting before `lo` are before, lines starting at or after `hi` are not.
+        let mut lo = 0;
+        let mut hi = self.len;
+
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            let start = self.line_start(mid)?;
+
+            if start >= hi {
+                hi = mid;
+                continue;
+            }
+
+            let mut records = Records::new(self.reader_at(start), self.delim, Source::Input);
+            let mut line = String::new();
+            let n = records.read(&mut line)?;
+
+            if is_before(&(self.key)(&line)) {
+                lo = start + n as u64;
+            } else {
+                hi = start;
+            }
+        }
+
+        Ok(lo)
+    }
+}
+
+/// Iterator returned by `SortedFile::range`.
+pub struct SortedRange<'a, F, R> {
+    file: &'a SortedFile<F>,
+    range: R,
+    records: Option<Records<io::BufReader<ReadAt<'a>>>>,
+    done: bool,
+}
+
+impl<'a, F, K, R> SortedRange<'a, F, R>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+    R: RangeBounds<K>,
+{
+    fn next_line(&mut self) -> Result<Option<String>> {
+        let file = self.file;
+
+        if self.records.is_none() {
+            let start = match self.range.start_bound() {
+                Bound::Included(s) => file.partition_point(|k| file.before(k, s))?,
+                Bound::Excluded(s) => file.partition_point(|k| !file.before(s, k))?,
+                Bound::Unbounded => 0,
+            };
+
+            let reader = file.reader_at(start);
+            self.records = Some(Records::new(reader, file.delim, Source::Input));
+        }
+
+        let mut line = String::new();
+        let records = self.records.as_mut().expect("records are opened above");
+
+        if records.read(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        let k = (file.key)(&line);
+
+        let after = match self.range.end_bound() {
+            Bound::Included(e) => file.before(e, &k),
+            Bound::Excluded(e) => !file.before(&k, e),
+            Bound::Unbounded => false,
+        };
+
+        if after {
+            return Ok(None);
+        }
+
+        Ok(Some(line))
+    }
+}
+
+impl<'a, F, K, R> Iterator for SortedRange<'a, F, R>
+where
+    F: Fn(&String) -> K,
+    K: Ord,
+    R: RangeBounds<K>,
+{
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let line = self.next_line().transpose();
+        self.done = !matches!(line, Some(Ok(_)));
+        line
+    }
+}
@@ -0,0 +1,544 @@
+use super::context::Context;
+use super::file_utils::ReadAt;
+use super::file_utils::Records;
+use super::file_utils::Source;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::vec;
+
+// The file is laid out as
+//
+//     data blocks      sorted lines, cut into blocks of about `block_size` bytes
+//     index            offset (u64), length (u64) and first line (u32 length, bytes) of each block
+//     bloom filter     number of hashes (u32) and bits, or nothing
+//     footer           offsets and lengths (u64) of the index and the bloom filter,
+//                      the delimiter (u8) and MAGIC
+//
+// with integers in little endian.
+const MAGIC: &[u8; 8] = b"EXSSTBL1";
+const FOOTER_LEN: u64 = 8 * 4 + 1 + 8;
+
+/// Layout of the file written by `sort_by_key_to_sstable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SsTableOptions {
+    block_size: u64,
+    bloom_bits_per_key: Option<u32>,
+}
+
+impl Default for SsTableOptions {
+    fn default() -> Self {
+        SsTableOptions {
+            block_size: 4096,
+            bloom_bits_per_key: Some(10),
+        }
+    }
+}
+
+impl SsTableOptions {
+    pub fn new() -> SsTableOptions {
+        SsTableOptions::default()
+    }
+
+    /// Bytes of lines per block. A block is read at once by lookups.
+    pub fn block_size(mut self, block_size: u64) -> SsTableOptions {
+        self.block_size = block_size.max(1);
+        self
+    }
+
+    /// Bits of bloom filter per line, or `None` for no bloom filter.
+    /// 10 bits give about 1% false positives.
+    pub fn bloom_filter(mut self, bits_per_key: Option<u32>) -> SsTableOptions {
+        self.bloom_bits_per_key = bits_per_key;
+        self
+    }
+}
+
+// FNV-1a, which unlike `DefaultHasher` gives the same hashes in every build. Integers are
+// hashed as little endian, and `usize` and `isize` as 64 bits, so that a bloom filter
+// written on one platform is read on any other.
+struct Fnv(u64);
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 = (self.0 ^ u64::from(*b)).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+}
+
+pub(crate) fn hash<K: Hash>(key: &K) -> u64 {
+    let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Bloom {
+    hashes: u32,
+    bits: Vec<u8>,
+}
+
+impl Bloom {
+    fn new(keys: u64, bits_per_key: u32) -> Bloom {
+        let bits = (keys * u64::from(bits_per_key)).max(64);
+        // ln(2) * bits per key minimizes false positives.
+        let hashes = ((f64::from(bits_per_key) * 0.69) as u32).clamp(1, 30);
+
+        Bloom {
+            hashes,
+            bits: vec![0; bits.div_ceil(8) as usize],
+        }
+    }
+
+    // Bit positions of a hash, by double hashing.
+    fn positions(&self, h: u64) -> impl Iterator<Item = usize> {
+        let len = self.bits.len() as u64 * 8;
+        let delta = h.rotate_left(31) | 1;
+        (0..u64::from(self.hashes))
+            .map(move |i| (h.wrapping_add(i.wrapping_mul(delta)) % len) as usize)
+    }
+
+    fn insert(&mut self, h: u64) {
+        for p in self.positions(h).collect::<Vec<usize>>() {
+            self.bits[p / 8] |= 1 << (p % 8);
+        }
+    }
+
+    fn may_contain(&self, h: u64) -> bool {
+        self.positions(h)
+            .all(|p| self.bits[p / 8] & (1 << (p % 8)) != 0)
+    }
+}
+
+impl Sorter {
+    /// Same as `sort_by_key`, writing an SSTable that `SsTable` can search by key.
+    ///
+    /// `reverse` does not apply: the table is always in ascending key order.
+    pub fn sort_by_key_to_sstable<T, F, K>(
+        &self,
+        fin: fs::File,
+        fout: T,
+        opts: SsTableOptions,
+        key: F,
+    ) -> Result<SortStats>
+    where
+        T: io::Write,
+        F: Fn(&String) -> K,
+        K: Ord + Hash,
+    {
+        let sorter = self.clone().reverse(false);
+
+        sorter.sort_then(fin, &key, None, |sorted, ctx| {
+            write_table(sorted, ctx, opts, fout, &key)
+        })
+    }
+}
+
+// Counts the bytes written to find the offsets of the sections.
+struct TableWriter<W: io::Write> {
+    inner: io::BufWriter<W>,
+    offset: u64,
+}
+
+impl<W: io::Write> TableWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<()> {
+        self.inner.write_all(buf).map_err(Error::write)?;
+        self.offset += buf.len() as u64;
+        Ok(())
+    }
+}
+
+fn write_table<T, F, K>(
+    sorted: &fs::File,
+    ctx: &Context,
+    opts: SsTableOptions,
+    fout: T,
+    key: &F,
+) -> Result<()>
+where
+    T: io::Write,
+    F: Fn(&String) -> K,
+    K: Ord + Hash,
+{
+    let delim = ctx.sorter.delim;
+    let mut records = Records::new(io::BufReader::new(sorted), delim, Source::Temp);
+    let mut writer = TableWriter {
+        inner: io::BufWriter::new(ctx.writer(fout)),
+        offset: 0,
+    };
+    let mut bloom = opts.bloom_bits_per_key.map(|bits| {
+        let mut keys = 0;
+        ctx.stats(|s| keys = s.records);
+        Bloom::new(keys, bits)
+    });
+    let mut index = vec![];
+    let mut first: Option<(u64, String)> = None;
+    let mut buf = String::new();
+
+    while records.read(&mut buf)? > 0 {
+        if let Some(bloom) = &mut bloom {
+            bloom.insert(hash(&key(&buf)));
+        }
+
+        if first.is_none() {
+            first = Some((writer.offset, buf.clone()));
+        }
+
+        writer.write(buf.as_bytes())?;
+        buf.clear();
+
+        let full = first
+            .as_ref()
+            .is_some_and(|(start, _)| writer.offset - start >= opts.block_size);
+
+        if full {
+            if let Some((start, line)) = first.take() {
+                index.push((start, writer.offset - start, line));
+            }
+        }
+    }
+
+    if let Some((start, line)) = first.take() {
+        index.push((start, writer.offset - start, line));
+    }
+
+    let index_offset = writer.offset;
+
+    for (offset, len, line) in &index {
+        writer.write(&offset.to_le_bytes())?;
+        writer.write(&len.to_le_bytes())?;
+        writer.write(&(line.len() as u32).to_le_bytes())?;
+        writer.write(line.as_bytes())?;
+    }
+
+    let bloom_offset = writer.offset;
+
+    if let Some(bloom) = &bloom {
+        writer.write(&bloom.hashes.to_le_bytes())?;
+        writer.write(&bloom.bits)?;
+    }
+
+    let end = writer.offset;
+    writer.write(&index_offset.to_le_bytes())?;
+    writer.write(&(bloom_offset - index_offset).to_le_bytes())?;
+    writer.write(&bloom_offset.to_le_bytes())?;
+    writer.write(&(end - bloom_offset).to_le_bytes())?;
+    writer.write(&[delim])?;
+    writer.write(MAGIC)?;
+    writer.inner.flush().map_err(Error::write)
+}
+
+struct Block<K> {
+    offset: u64,
+    len: u64,
+    first_key: K,
+}
+
+/// A file written by `sort_by_key_to_sstable`, opened for lookups by key.
+///
+/// `key` must be the key function the file was written with.
+pub struct SsTable<F, K> {
+    file: fs::File,
+    blocks: Vec<Block<K>>,
+    bloom: Option<Bloom>,
+    delim: u8,
+    key: F,
+}
+
+fn invalid() -> Error {
+    Error::Read(io::Error::new(io::ErrorKind::InvalidData, "not an sstable"))
+}
+
+// Positional, so that lookups from several threads do not move each other's reads.
+fn read_at(file: &fs::File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0; len as usize];
+    ReadAt::new(file, offset)
+        .read_exact(&mut buf)
+        .map_err(Error::Read)?;
+    Ok(buf)
+}
+
+// Reads little endian integers from a section of the file.
+struct Cursor<'a>(&'a [u8]);
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(invalid());
+        }
+
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(b))
+    }
+}
+
+impl<F, K> SsTable<F, K>
+where
+    F: Fn(&String) -> K,
+    K: Ord + Hash,
+{
+    pub fn open(file: fs::File, key: F) -> Result<SsTable<F, K>> {
+        let len = file.metadata().map_err(Error::Read)?.len();
+
+        if len < FOOTER_LEN {
+            return Err(invalid());
+        }
+
+        let footer = read_at(&file, len - FOOTER_LEN, FOOTER_LEN)?;
+        let mut c = Cursor(&footer);
+        let (index_offset, index_len) = (c.u64()?, c.u64()?);
+        let (bloom_offset, bloom_len) = (c.u64()?, c.u64()?);
+        let delim = c.take(1)?[0];
+
+        if c.take(8)? != MAGIC {
+            return Err(invalid());
+        }
+
+        // The index is followed by the bloom filter, then the footer.
+        let index_end = index_offset.checked_add(index_len);
+        let bloom_end = bloom_offset.checked_add(bloom_len);
+
+        if index_end.is_none_or(|end| end > bloom_offset)
+            || bloom_end.is_none_or(|end| end > len - FOOTER_LEN)
+        {
+            return Err(invalid());
+        }
+
+        let index = read_at(&file, index_offset, index_len)?;
+        let mut c = Cursor(&index);
+        let mut blocks = vec![];
+        let mut blocks_end = 0;
+
+        while !c.0.is_empty() {
+            let (offset, len) = (c.u64()?, c.u64()?);
+            let n = c.u32()? as usize;
+            let line = String::from_utf8(c.take(n)?.to_vec()).map_err(|_| invalid())?;
+
+            // Blocks follow each other before the index.
+            let end = offset.checked_add(len);
+
+            if offset < blocks_end || end.is_none_or(|end| end > index_offset) {
+                return Err(invalid());
+            }
+
+            blocks_end = offset + len;
+
+            blocks.push(Block {
+                offset,
+                len,
+                first_key: key(&line),
+            });
+        }
+
+        let bloom = if bloom_len > 0 {
+            let bytes = read_at(&file, bloom_offset, bloom_len)?;
+            let mut c = Cursor(&bytes);
+            let hashes = c.u32()?;
+
+            if c.0.is_empty() {
+                return Err(invalid());
+            }
+
+            Some(Bloom {
+                hashes,
+                bits: c.0.to_vec(),
+            })
+        } else {
+            None
+        };
+
+        Ok(SsTable {
+            file,
+            blocks,
+            bloom,
+            delim,
+            key,
+        })
+    }
+
+    /// Lines with key `k`, in file order.
+    pub fn get(&self, k: &K) -> Result<Vec<String>> {
+        if !self.may_contain(k) {
+            return Ok(vec![]);
+        }
+
+        self.range((Bound::Included(k), Bound::Included(k)))
+            .collect()
+    }
+
+    /// False when the bloom filter rules out lines with key `k`.
+    pub fn may_contain(&self, k: &K) -> bool {
+        match &self.bloom {
+            Some(bloom) => bloom.may_contain(hash(k)),
+            None => true,
+        }
+    }
+
+    /// Lines with keys in `range`, in key order. Blocks are read as the iterator advances.
+    pub fn range<'a, R>(&'a self, range: R) -> SsTableRange<'a, F, K, R>
+    where
+        R: RangeBounds<K>,
+    {
+        let block = match range.start_bound() {
+            Bound::Included(s) | Bound::Excluded(s) => self
+                .blocks
+                .partition_point(|b| b.first_key < *s)
+                .saturating_sub(1),
+            Bound::Unbounded => 0,
+        };
+
+        SsTableRange {
+            table: self,
+            range,
+            block,
+            lines: vec![].into_iter(),
+            done: false,
+        }
+    }
+
+    fn read_block(&self, i: usize) -> Result<Vec<String>> {
+        let block = &self.blocks[i];
+        let bytes = read_at(&self.file, block.offset, block.len)?;
+        let mut records = Records::new(&bytes[..], self.delim, Source::Input);
+        let mut lines = vec![];
+        let mut buf = String::new();
+
+        while records.read(&mut buf)? > 0 {
+            lines.push(std::mem::take(&mut buf));
+        }
+
+        Ok(lines)
+    }
+}
+
+/// Iterator returned by `SsTable::range`.
+pub struct SsTableRange<'a, F, K, R> {
+    table: &'a SsTable<F, K>,
+    range: R,
+    block: usize,
+    lines: vec::IntoIter<String>,
+    done: bool,
+}
+
+impl<'a, F, K, R> Iterator for SsTableRange<'a, F, K, R>
+where
+    F: Fn(&String) -> K,
+    K: Ord + Hash,
+    R: RangeBounds<K>,
+{
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let line = match self.lines.next() {
+                Some(line) => line,
+                None if self.block < self.table.blocks.len() => {
+                    match self.table.read_block(self.block) {
+                        Ok(lines) => self.lines = lines.into_iter(),
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    }
+
+                    self.block += 1;
+                    continue;
+                }
+                None => break,
+            };
+
+            let k = (self.table.key)(&line);
+
+            let before = match self.range.start_bound() {
+                Bound::Included(s) => k < *s,
+                Bound::Excluded(s) => k <= *s,
+                Bound::Unbounded => false,
+            };
+
+            if before {
+                continue;
+            }
+
+            let after = match self.range.end_bound() {
+                Bound::Included(e) => k > *e,
+                Bound::Excluded(e) => k >= *e,
+                Bound::Unbounded => false,
+            };
+
+            if after {
+                self.done = true;
+                break;
+            }
+
+            return Some(Ok(line));
+        }
+
+        None
+    }
+}
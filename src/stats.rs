@@ -0,0 +1,28 @@
+use std::time::Duration;
+
+/// Counters collected during a sort or merge call.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SortStats {
+    /// Records read from the input.
+    pub records: u64,
+    /// Lines for which the key function failed, with `try_sort_by_key`.
+    pub rejected: u64,
+    /// Sorted runs produced from the input.
+    pub runs: u64,
+    /// Merges of two or more runs.
+    pub merges: u64,
+    /// Deepest merge pass. 0 when the input was sorted in a single run.
+    pub merge_passes: u64,
+    /// Bytes written to temporary files.
+    pub bytes_spilled: u64,
+    /// Largest number of record bytes held in memory at once.
+    pub peak_buffered_bytes: u64,
+    /// Time spent splitting the input and sorting runs.
+    pub run_duration: Duration,
+    /// Time spent merging runs.
+    pub merge_duration: Duration,
+    /// Time spent writing the output.
+    pub output_duration: Duration,
+    /// Outputs written by `sort_by_key_partitioned`.
+    pub partitions: u64,
+}
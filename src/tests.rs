@@ -1,10 +1,39 @@
+use super::check_sorted_by_key;
+use super::is_cancelled;
+use super::is_sorted_by_key;
+use super::merge;
+use super::merge_by_key;
+use super::reverse_merge_by_key;
 use super::reverse_sort_by_key;
+use super::reverse_top_k_by_key;
 use super::sort_by_key;
+use super::sort_iter_by_key;
+use super::sort_iter_by_key_with;
+use super::top_k_by_key;
+use super::try_sort_by_key;
+use super::CancelToken;
+use super::Codec;
+use super::Disorder;
+use super::Error;
+use super::JoinKind;
+use super::NaturalRuns;
+use super::OnKeyError;
+use super::PartitionBy;
+use super::Position;
+use super::Progress;
+use super::SetOp;
+use super::SortedFile;
+use super::Sorter;
+use super::SsTable;
+use super::SsTableOptions;
 use indoc::indoc;
 use std::io;
 use std::io::Seek;
 use std::io::Write;
+use std::ops::Bound;
 use std::str;
+use std::sync::Arc;
+use std::sync::Mutex;
 
 static CSV: &str = indoc! {"
     0,Golf,189
@@ -523,7 +552,7 @@ fn test_sort_empty() {
 #[test]
 fn test_sort_one_line() {
     let mut fin = tempfile::tempfile().unwrap();
-    write!(fin, "0,Golf,189\n").unwrap();
+    writeln!(fin, "0,Golf,189").unwrap();
     fin.seek(io::SeekFrom::Start(0)).unwrap();
     let mut buf = Vec::new();
     let fout = Box::new(&mut buf);
@@ -573,3 +602,1802 @@ fn test_sort_three_lines() {
         str::from_utf8(&buf).unwrap()
     );
 }
+
+#[test]
+fn test_merge() {
+    let inputs = vec![
+        "0,Golf,189\n10,Delta,170\n".as_bytes(),
+        "1,Yankee,157\n2,Uniform,158\n".as_bytes(),
+        "".as_bytes(),
+        "11,Zulu,118\n12,Sierra,186\n13,Charlie,195\n".as_bytes(),
+    ];
+    let mut buf = Vec::new();
+
+    merge_by_key(inputs, &mut buf, |line| {
+        let cols = line.split(',').collect::<Vec<&str>>();
+        cols[0].parse::<i32>().unwrap()
+    })
+    .unwrap();
+
+    assert_eq!(
+        indoc! {"
+            0,Golf,189
+            1,Yankee,157
+            2,Uniform,158
+            10,Delta,170
+            11,Zulu,118
+            12,Sierra,186
+            13,Charlie,195
+        "},
+        str::from_utf8(&buf).unwrap()
+    );
+}
+
+#[test]
+fn test_reverse_merge() {
+    let inputs = vec![
+        "10,Delta,170\n0,Golf,189\n".as_bytes(),
+        "13,Charlie,195\n2,Uniform,158\n1,Yankee,157\n".as_bytes(),
+    ];
+    let mut buf = Vec::new();
+
+    reverse_merge_by_key(inputs, &mut buf, |line| {
+        let cols = line.split(',').collect::<Vec<&str>>();
+        cols[0].parse::<i32>().unwrap()
+    })
+    .unwrap();
+
+    assert_eq!(
+        indoc! {"
+            13,Charlie,195
+            10,Delta,170
+            2,Uniform,158
+            1,Yankee,157
+            0,Golf,189
+        "},
+        str::from_utf8(&buf).unwrap()
+    );
+}
+
+#[test]
+fn test_merge_equal_keys_in_input_order() {
+    let inputs = vec!["b,1\nb,2\n".as_bytes(), "a,3\nb,4\n".as_bytes()];
+    let mut buf = Vec::new();
+
+    merge_by_key(inputs, &mut buf, |line| {
+        line.split(',').next().unwrap().to_string()
+    })
+    .unwrap();
+
+    assert_eq!("a,3\nb,1\nb,2\nb,4\n", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_merge_verify() {
+    let inputs = vec!["1\n3\n".as_bytes(), "2\n5\n4\n".as_bytes()];
+    let mut buf = Vec::new();
+
+    let err = Sorter::new()
+        .verify(true)
+        .merge_by_key(inputs, &mut buf, |line| {
+            line.trim_end().parse::<i32>().unwrap()
+        })
+        .unwrap_err();
+
+    assert!(matches!(
+        err,
+        Error::NotSorted {
+            input: 1,
+            line_num: 3
+        }
+    ));
+    assert_eq!("input 1 is not sorted at line 3", err.to_string());
+
+    // Duplicate keys are sorted, `unique` drops them.
+    let inputs = vec!["a\na\nb\n".as_bytes(), "a\n".as_bytes()];
+    let mut buf = Vec::new();
+
+    Sorter::new()
+        .unique(true)
+        .verify(true)
+        .merge_by_key(inputs, &mut buf, |line| line.to_string())
+        .unwrap();
+
+    assert_eq!("a\nb\n", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_sort_unique() {
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "b,1\na,2\nb,3\nc,4\na,5\n").unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    Sorter::new()
+        .capacity(8)
+        .unique(true)
+        .sort_by_key(fin, &mut buf, |line| {
+            line.split(',').next().unwrap().to_string()
+        })
+        .unwrap();
+
+    assert_eq!("a,2\nb,1\nc,4\n", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_sort_stable() {
+    for cap in &[8, 1024] {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "b,3\na,2\nb,1\nc,4\na,5\n").unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+
+        Sorter::new()
+            .capacity(*cap)
+            .reverse(true)
+            .sort_by_key(fin, &mut buf, |line| {
+                line.split(',').next().unwrap().to_string()
+            })
+            .unwrap();
+
+        assert_eq!("c,4\nb,3\nb,1\na,2\na,5\n", str::from_utf8(&buf).unwrap());
+    }
+}
+
+#[test]
+fn test_sort_unstable() {
+    for cap in &[8, 1024] {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "b,3\na,5\nb,1\nc,4\na,2\n").unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+
+        Sorter::new()
+            .capacity(*cap)
+            .stable(false)
+            .sort_by_key(fin, &mut buf, |line| {
+                line.split(',').next().unwrap().to_string()
+            })
+            .unwrap();
+
+        assert_eq!("a,2\na,5\nb,1\nb,3\nc,4\n", str::from_utf8(&buf).unwrap());
+    }
+}
+
+#[test]
+fn test_check_sorted() {
+    let key = |line: &String| line.split(',').next().unwrap().parse::<i32>().unwrap();
+
+    assert!(!is_sorted_by_key(CSV.as_bytes(), |line| line.len()).unwrap());
+    assert!(is_sorted_by_key("1,a\n2,b\n2,a\n3,c\n".as_bytes(), key).unwrap());
+    assert!(is_sorted_by_key("".as_bytes(), key).unwrap());
+
+    assert_eq!(
+        Some(Disorder {
+            line_num: 13,
+            offset: 159,
+            line: "2,Uniform,158\n".to_string()
+        }),
+        check_sorted_by_key(CSV.as_bytes(), key).unwrap()
+    );
+}
+
+#[test]
+fn test_check_sorted_with_options() {
+    let key = |line: &String| line.split(',').next().unwrap().parse::<i32>().unwrap();
+    let input = "3,c\n2,b\n2,a\n1,a\n";
+
+    assert!(!Sorter::new()
+        .is_sorted_by_key(input.as_bytes(), key)
+        .unwrap());
+    assert!(Sorter::new()
+        .reverse(true)
+        .is_sorted_by_key(input.as_bytes(), key)
+        .unwrap());
+    assert!(Sorter::new()
+        .reverse(true)
+        .stable(false)
+        .is_sorted_by_key(input.as_bytes(), key)
+        .unwrap());
+
+    assert_eq!(
+        Some(Disorder {
+            line_num: 3,
+            offset: 8,
+            line: "2,a\n".to_string()
+        }),
+        Sorter::new()
+            .reverse(true)
+            .unique(true)
+            .check_sorted_by_key(input.as_bytes(), key)
+            .unwrap()
+    );
+
+    assert_eq!(
+        Some(Disorder {
+            line_num: 2,
+            offset: 4,
+            line: "2,b\n".to_string()
+        }),
+        Sorter::new()
+            .stable(false)
+            .check_sorted_by_key("2,c\n2,b\n".as_bytes(), key)
+            .unwrap()
+    );
+}
+
+#[test]
+fn test_top_k() {
+    for cap in &[10, 1024] {
+        let mut buf = Vec::new();
+
+        top_k_by_key(CSV.as_bytes(), &mut buf, *cap, 3, |line| {
+            let cols = line.split(',').collect::<Vec<&str>>();
+            cols[2].to_string()
+        })
+        .unwrap();
+
+        assert_eq!(
+            indoc! {"
+                5,Mike,110
+                21,Bravo,111
+                6,Whiskey,116
+            "},
+            str::from_utf8(&buf).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_reverse_top_k() {
+    for cap in &[10, 1024] {
+        let mut buf = Vec::new();
+
+        reverse_top_k_by_key(CSV.as_bytes(), &mut buf, *cap, 3, |line| {
+            let cols = line.split(',').collect::<Vec<&str>>();
+            cols[1].to_string()
+        })
+        .unwrap();
+
+        assert_eq!(
+            indoc! {"
+                11,Zulu,118
+                1,Yankee,157
+                23,X-ray,167
+            "},
+            str::from_utf8(&buf).unwrap()
+        );
+    }
+}
+
+#[test]
+fn test_top_k_more_than_lines() {
+    let mut buf = Vec::new();
+
+    top_k_by_key("b\na\n".as_bytes(), &mut buf, 1024, 5, |line| line.clone()).unwrap();
+    assert_eq!("a\nb\n", str::from_utf8(&buf).unwrap());
+
+    buf.clear();
+    top_k_by_key("b\na\n".as_bytes(), &mut buf, 1024, 0, |line| line.clone()).unwrap();
+    assert_eq!("", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_top_k_unique() {
+    for cap in &[4, 1024] {
+        let mut buf = Vec::new();
+
+        Sorter::new()
+            .capacity(*cap)
+            .unique(true)
+            .top_k_by_key(
+                "b,1\na,2\nb,3\na,4\nc,5\nd,6\n".as_bytes(),
+                &mut buf,
+                3,
+                |line| line.split(',').next().unwrap().to_string(),
+            )
+            .unwrap();
+
+        assert_eq!("a,2\nb,1\nc,5\n", str::from_utf8(&buf).unwrap());
+    }
+}
+
+#[test]
+fn test_sort_nul_terminated() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "b\n2\0c\n3\0a\n1\0").unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    Sorter::new()
+        .capacity(4)
+        .delimiter(b'\0')
+        .temp_dir(dir.path())
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap();
+
+    assert_eq!("a\n1\0b\n2\0c\n3\0", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_sort_without_trailing_newline() {
+    for cap in [1, 4, 10, 1024] {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "c\nb\na").unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+
+        sort_by_key(fin, &mut buf, cap, |line| line.clone()).unwrap();
+
+        assert_eq!("a\nb\nc\n", str::from_utf8(&buf).unwrap());
+    }
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV.trim_end()).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    sort_by_key(fin, &mut buf, 10, |line| {
+        let cols = line.split(',').collect::<Vec<&str>>();
+        cols[2].to_string()
+    })
+    .unwrap();
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut expected = Vec::new();
+
+    sort_by_key(fin, &mut expected, 10, |line| {
+        let cols = line.split(',').collect::<Vec<&str>>();
+        cols[2].to_string()
+    })
+    .unwrap();
+
+    assert_eq!(
+        str::from_utf8(&expected).unwrap(),
+        str::from_utf8(&buf).unwrap()
+    );
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "b\0c\0a").unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    Sorter::new()
+        .capacity(1)
+        .delimiter(b'\0')
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap();
+
+    assert_eq!("a\0b\0c\0", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_merge_and_top_k_without_trailing_newline() {
+    let inputs = vec!["a\nc".as_bytes(), "b\nd".as_bytes()];
+    let mut buf = Vec::new();
+
+    merge_by_key(inputs, &mut buf, |line| line.clone()).unwrap();
+
+    assert_eq!("a\nb\nc\nd\n", str::from_utf8(&buf).unwrap());
+
+    for cap in [1, 1024] {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "c\nb\na").unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+
+        top_k_by_key(fin, &mut buf, cap, 2, |line| line.clone()).unwrap();
+
+        assert_eq!("a\nb\n", str::from_utf8(&buf).unwrap());
+    }
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_sort_async() {
+    let mut buf = Vec::new();
+
+    super::sort_by_key_async(CSV.as_bytes(), &mut buf, 10, |line| {
+        let cols = line.split(',').collect::<Vec<&str>>();
+        cols[2].to_string()
+    })
+    .await
+    .unwrap();
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut expected = Vec::new();
+
+    sort_by_key(fin, &mut expected, 10, |line| {
+        let cols = line.split(',').collect::<Vec<&str>>();
+        cols[2].to_string()
+    })
+    .unwrap();
+
+    assert_eq!(
+        str::from_utf8(&expected).unwrap(),
+        str::from_utf8(&buf).unwrap()
+    );
+}
+
+#[cfg(feature = "async")]
+#[tokio::test]
+async fn test_reverse_sort_async() {
+    let mut buf = Vec::new();
+
+    super::reverse_sort_by_key_async("b\nc\na\n".as_bytes(), &mut buf, 1024, |line| line.clone())
+        .await
+        .unwrap();
+
+    assert_eq!("c\nb\na\n", str::from_utf8(&buf).unwrap());
+}
+
+#[test]
+fn test_sort_progress() {
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+    let events = Arc::new(Mutex::new(Vec::<Progress>::new()));
+    let events2 = events.clone();
+
+    Sorter::new()
+        .capacity(100)
+        .max_fan_in(2)
+        .progress(move |p| events2.lock().unwrap().push(p.clone()))
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap();
+
+    let events = events.lock().unwrap();
+    let last = events.last().unwrap();
+    let len = CSV.len() as u64;
+
+    assert_eq!(len, last.input_bytes);
+    assert_eq!(len, last.bytes_read);
+    assert_eq!(len, last.bytes_written);
+    assert_eq!(4, last.runs);
+    assert_eq!(2, events.iter().map(|p| p.merge_pass).max().unwrap());
+    assert!(events
+        .windows(2)
+        .all(|w| w[0].bytes_read <= w[1].bytes_read));
+}
+
+#[test]
+fn test_merge_progress() {
+    let inputs = vec!["a\nc\n".as_bytes(), "b\n".as_bytes()];
+    let mut buf = Vec::new();
+    let last = Arc::new(Mutex::new(Progress::default()));
+    let last2 = last.clone();
+
+    Sorter::new()
+        .progress(move |p| *last2.lock().unwrap() = p.clone())
+        .merge_by_key(inputs, &mut buf, |line| line.clone())
+        .unwrap();
+
+    assert_eq!(
+        Progress {
+            input_bytes: 0,
+            bytes_read: 6,
+            runs: 2,
+            merge_pass: 1,
+            bytes_written: 6,
+        },
+        *last.lock().unwrap()
+    );
+}
+
+#[test]
+fn test_sort_stats() {
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    let stats = sort_by_key(fin, &mut buf, 100, |line| line.clone()).unwrap();
+
+    assert_eq!(26, stats.records);
+    assert_eq!(4, stats.runs);
+    assert_eq!(1, stats.merges);
+    assert_eq!(1, stats.merge_passes);
+    assert!(stats.peak_buffered_bytes <= 100);
+    assert!(stats.bytes_spilled > 2 * CSV.len() as u64);
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    buf.clear();
+
+    let stats = Sorter::new()
+        .capacity(100)
+        .max_fan_in(2)
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap();
+
+    assert_eq!(26, stats.records);
+    assert_eq!(4, stats.runs);
+    assert_eq!(3, stats.merges);
+    assert_eq!(2, stats.merge_passes);
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    buf.clear();
+
+    let stats = sort_by_key(fin, &mut buf, 1024, |line| line.clone()).unwrap();
+
+    assert_eq!(26, stats.records);
+    assert_eq!(1, stats.runs);
+    assert_eq!(0, stats.merges);
+    assert_eq!(0, stats.merge_passes);
+    assert_eq!(CSV.len() as u64, stats.peak_buffered_bytes);
+    assert_eq!(CSV.len() as u64, stats.bytes_spilled);
+}
+
+#[test]
+fn test_merge_and_top_k_stats() {
+    let inputs = vec!["a\nc\n".as_bytes(), "b\n".as_bytes()];
+    let mut buf = Vec::new();
+    let stats = merge_by_key(inputs, &mut buf, |line| line.clone()).unwrap();
+
+    assert_eq!(3, stats.records);
+    assert_eq!(2, stats.runs);
+    assert_eq!(1, stats.merge_passes);
+    assert_eq!(0, stats.bytes_spilled);
+
+    for cap in &[10, 1024] {
+        buf.clear();
+        let stats = top_k_by_key(CSV.as_bytes(), &mut buf, *cap, 3, |line| line.clone()).unwrap();
+        assert_eq!(26, stats.records);
+    }
+}
+
+#[test]
+fn test_sort_cancelled() {
+    let token = CancelToken::new();
+    token.cancel();
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    let err = Sorter::new()
+        .cancel_token(token)
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(is_cancelled(&err));
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_sort_cancelled_while_running() {
+    let token = CancelToken::new();
+    let token2 = token.clone();
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut buf = Vec::new();
+
+    let err = Sorter::new()
+        .capacity(10)
+        .cancel_token(token)
+        .progress(move |p| {
+            if p.runs == 3 {
+                token2.cancel();
+            }
+        })
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(is_cancelled(&err));
+
+    let inputs = vec!["a\n".as_bytes()];
+    let token = CancelToken::new();
+    token.cancel();
+
+    let err = Sorter::new()
+        .cancel_token(token)
+        .merge_by_key(inputs, &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(is_cancelled(&err));
+    assert!(!is_cancelled(&Error::Write(io::Error::other("other"))));
+}
+
+#[test]
+fn test_sort_resumed() {
+    let dir = tempfile::tempdir().unwrap();
+    let work_dir = dir.path().join("work");
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    let mut expected = Vec::new();
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let full = Sorter::new()
+        .capacity(10)
+        .sort_by_key(fin.try_clone().unwrap(), &mut expected, |line| line.clone())
+        .unwrap();
+
+    let token = CancelToken::new();
+    let token2 = token.clone();
+    let mut buf = Vec::new();
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let err = Sorter::new()
+        .capacity(10)
+        .work_dir(&work_dir)
+        .cancel_token(token)
+        .progress(move |p| {
+            if p.runs == 3 {
+                token2.cancel();
+            }
+        })
+        .sort_by_key(fin.try_clone().unwrap(), &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(is_cancelled(&err));
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let err = Sorter::new()
+        .capacity(20)
+        .work_dir(&work_dir)
+        .sort_by_key(fin.try_clone().unwrap(), &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(matches!(&err, Error::TempSpace(e) if e.kind() == io::ErrorKind::InvalidInput));
+
+    // Another input of the same length.
+    let mut other = tempfile::tempfile().unwrap();
+    write!(other, "{}", CSV.replacen('0', "1", 1)).unwrap();
+    other.seek(io::SeekFrom::Start(0)).unwrap();
+    let err = Sorter::new()
+        .capacity(10)
+        .work_dir(&work_dir)
+        .sort_by_key(other, &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(matches!(&err, Error::TempSpace(e) if e.kind() == io::ErrorKind::InvalidInput));
+
+    // Files that are not part of the sort are left in place.
+    std::fs::write(work_dir.join("keep.run"), "keep").unwrap();
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let resumed = Sorter::new()
+        .capacity(10)
+        .work_dir(&work_dir)
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap();
+
+    assert_eq!(
+        str::from_utf8(&expected).unwrap(),
+        str::from_utf8(&buf).unwrap()
+    );
+    assert!(resumed.runs < full.runs);
+    assert_eq!(1, std::fs::read_dir(&work_dir).unwrap().count());
+    assert!(work_dir.join("keep.run").exists());
+}
+
+struct Failing;
+
+impl io::Read for Failing {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        Err(io::Error::other("read failed"))
+    }
+}
+
+impl io::Write for Failing {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::other("write failed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_invalid_encoding() {
+    for cap in [4, 1024] {
+        let mut fin = tempfile::tempfile().unwrap();
+        fin.write_all(b"b\nc\n\xff\na\n").unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+
+        let err = sort_by_key(fin, &mut buf, cap, |line| line.clone()).unwrap_err();
+
+        assert!(matches!(
+            err,
+            Error::InvalidEncoding {
+                line_num: 3,
+                offset: 4
+            }
+        ));
+    }
+
+    let err = check_sorted_by_key(&b"a\n\xffb\n"[..], |line| line.clone()).unwrap_err();
+    assert_eq!("line 2 at byte 2 is not valid UTF-8", err.to_string());
+}
+
+#[test]
+fn test_read_and_write_errors() {
+    let mut buf = Vec::new();
+    let err = merge_by_key(vec![Failing], &mut buf, |line| line.clone()).unwrap_err();
+    assert!(matches!(&err, Error::Read(e) if e.to_string() == "read failed"));
+
+    let err = top_k_by_key("b\na\n".as_bytes(), Failing, 1024, 1, |line| line.clone()).unwrap_err();
+    assert!(matches!(&err, Error::Write(e) if e.to_string() == "write failed"));
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let err = sort_by_key(fin, Failing, 10, |line| line.clone()).unwrap_err();
+    assert!(matches!(err, Error::Write(_)));
+
+    let err = io::Error::from(err);
+    assert_eq!(io::ErrorKind::Other, err.kind());
+    assert_eq!("failed to write output: write failed", err.to_string());
+
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing");
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+
+    let err = Sorter::new()
+        .capacity(10)
+        .temp_dir(missing)
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(matches!(err, Error::TempSpace(_)));
+}
+
+fn parse_third_col(line: &str) -> Result<u32, Box<dyn std::error::Error + Send + Sync>> {
+    let col = line.trim_end().split(',').nth(2).ok_or("missing column")?;
+    Ok(col.parse()?)
+}
+
+#[test]
+fn test_try_sort() {
+    let input = "a,x,3\nb,x\nc,x,1\nd,x,y\ne,x,2\n";
+    let new_input = || {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "{}", input).unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        fin
+    };
+
+    let mut buf = Vec::new();
+    let err =
+        try_sort_by_key(new_input(), &mut buf, 4, OnKeyError::Abort, parse_third_col).unwrap_err();
+
+    assert!(matches!(
+        err,
+        Error::Key {
+            line_num: 2,
+            offset: 6,
+            ..
+        }
+    ));
+    assert_eq!(
+        "key function failed at line 2 at byte 6: missing column",
+        err.to_string()
+    );
+
+    let mut buf = Vec::new();
+    let stats =
+        try_sort_by_key(new_input(), &mut buf, 4, OnKeyError::Skip, parse_third_col).unwrap();
+
+    assert_eq!("c,x,1\ne,x,2\na,x,3\n", str::from_utf8(&buf).unwrap());
+    assert_eq!(2, stats.rejected);
+    assert_eq!(3, stats.records);
+
+    let mut buf = Vec::new();
+    let mut rejects = Vec::new();
+
+    Sorter::new()
+        .reverse(true)
+        .try_sort_by_key(
+            new_input(),
+            &mut buf,
+            OnKeyError::Reject(&mut rejects),
+            parse_third_col,
+        )
+        .unwrap();
+
+    assert_eq!("a,x,3\ne,x,2\nc,x,1\n", str::from_utf8(&buf).unwrap());
+    assert_eq!("b,x\nd,x,y\n", str::from_utf8(&rejects).unwrap());
+}
+
+fn sort_partitioned(input: &str, by: PartitionBy) -> (Vec<String>, u64) {
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", input).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let outputs = Arc::new(Mutex::new(vec![]));
+    let outputs2 = outputs.clone();
+
+    let stats = Sorter::new()
+        .capacity(4)
+        .sort_by_key_partitioned(
+            fin,
+            by,
+            |i| {
+                let mut outputs = outputs2.lock().unwrap();
+                assert_eq!(i, outputs.len());
+                outputs.push(tempfile::tempfile()?);
+                outputs[i].try_clone()
+            },
+            |line| line.split(',').next().unwrap().to_string(),
+        )
+        .unwrap();
+
+    let parts = outputs
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .map(|f| {
+            f.seek(io::SeekFrom::Start(0)).unwrap();
+            io::read_to_string(f).unwrap()
+        })
+        .collect();
+
+    (parts, stats.partitions)
+}
+
+#[test]
+fn test_sort_partitioned() {
+    let input = "c,1\na,1\nb,1\nb,2\nb,3\nd,1\n";
+
+    let (parts, n) = sort_partitioned(input, PartitionBy::records(2));
+    assert_eq!(vec!["a,1\nb,1\n", "b,2\nb,3\n", "c,1\nd,1\n"], parts);
+    assert_eq!(3, n);
+
+    let (parts, _) = sort_partitioned(input, PartitionBy::records(2).keep_groups(true));
+    assert_eq!(vec!["a,1\nb,1\nb,2\nb,3\n", "c,1\nd,1\n"], parts);
+
+    let (parts, _) = sort_partitioned(input, PartitionBy::bytes(9));
+    assert_eq!(vec!["a,1\nb,1\n", "b,2\nb,3\n", "c,1\nd,1\n"], parts);
+
+    let (parts, _) = sort_partitioned(input, PartitionBy::bytes(1));
+    assert_eq!(6, parts.len());
+
+    let (parts, n) = sort_partitioned("", PartitionBy::bytes(1));
+    assert!(parts.is_empty());
+    assert_eq!(0, n);
+}
+
+fn sort_ranges(sorter: Sorter, input: &str, n: usize) -> (Vec<String>, Vec<u32>) {
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", input).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    sort_file_ranges(sorter, fin, n).unwrap()
+}
+
+fn sort_file_ranges(
+    sorter: Sorter,
+    fin: std::fs::File,
+    n: usize,
+) -> Result<(Vec<String>, Vec<u32>), Error> {
+    let outputs = Arc::new(Mutex::new(vec![]));
+    let outputs2 = outputs.clone();
+
+    let ranges = sorter.sort_by_key_ranges(
+        fin,
+        n,
+        |_| {
+            let mut outputs = outputs2.lock().unwrap();
+            outputs.push(tempfile::tempfile()?);
+            outputs.last().unwrap().try_clone()
+        },
+        |line| line.trim_end().parse::<u32>().unwrap(),
+    )?;
+
+    let parts = outputs
+        .lock()
+        .unwrap()
+        .iter_mut()
+        .map(|f| {
+            f.seek(io::SeekFrom::Start(0)).unwrap();
+            io::read_to_string(f).unwrap()
+        })
+        .collect();
+
+    Ok((parts, ranges.splitters))
+}
+
+#[test]
+fn test_sort_ranges() {
+    let input = (0..1000)
+        .map(|i| format!("{}\n", i * 7919 % 1000))
+        .collect::<String>();
+
+    for desc in [false, true] {
+        let sorter = Sorter::new().capacity(500).reverse(desc);
+        let (parts, splitters) = sort_ranges(sorter, &input, 4);
+
+        assert_eq!(3, splitters.len());
+        assert_eq!(4, parts.len());
+
+        let mut expected = (0..1000).collect::<Vec<u32>>();
+
+        if desc {
+            expected.reverse();
+        }
+
+        let all = parts
+            .iter()
+            .flat_map(|p| p.lines().map(|l| l.parse::<u32>().unwrap()))
+            .collect::<Vec<u32>>();
+
+        assert_eq!(expected, all);
+
+        for (i, part) in parts.iter().enumerate() {
+            let n = part.lines().count();
+            assert!((150..=350).contains(&n), "range {} has {} lines", i, n);
+
+            let first = part.lines().next().unwrap().parse::<u32>().unwrap();
+
+            if i > 0 {
+                assert_eq!(splitters[i - 1], first);
+            }
+        }
+    }
+
+    let (parts, splitters) = sort_ranges(Sorter::new(), "1\n1\n1\n2\n", 4);
+    assert_eq!(vec![2], splitters);
+    assert_eq!(vec!["1\n1\n1\n", "2\n"], parts);
+
+    let (parts, splitters) = sort_ranges(Sorter::new(), "", 3);
+    assert!(splitters.is_empty());
+    assert_eq!(vec![""], parts);
+}
+
+#[test]
+fn test_sort_ranges_resumed() {
+    let mut fin = tempfile::tempfile().unwrap();
+
+    for i in 0..200 {
+        writeln!(fin, "{}", i * 7919 % 200).unwrap();
+    }
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let expected = sort_file_ranges(Sorter::new().capacity(100), fin.try_clone().unwrap(), 4);
+    let (expected, splitters) = expected.unwrap();
+    assert_eq!(3, splitters.len());
+
+    // Cancelled while generating runs, then while merging them.
+    for cancel_at in [|p: &Progress| p.runs == 5, |p: &Progress| p.merge_pass == 1] {
+        let dir = tempfile::tempdir().unwrap();
+        let sorter = Sorter::new().capacity(100).work_dir(dir.path());
+        let token = CancelToken::new();
+        let token2 = token.clone();
+
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let err = sort_file_ranges(
+            sorter.clone().cancel_token(token).progress(move |p| {
+                if cancel_at(p) {
+                    token2.cancel();
+                }
+            }),
+            fin.try_clone().unwrap(),
+            4,
+        )
+        .unwrap_err();
+        assert!(is_cancelled(&err));
+
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let (parts, resumed) = sort_file_ranges(sorter, fin.try_clone().unwrap(), 4).unwrap();
+
+        assert_eq!(3, resumed.len());
+        assert_eq!(expected.concat(), parts.concat());
+
+        for part in &parts {
+            assert!((25..=75).contains(&part.lines().count()), "{:?}", parts);
+        }
+    }
+}
+
+#[test]
+fn test_sort_iter() {
+    let values = ["c", "a\nb", "e", "b", "d"];
+
+    for cap in [1, 10, 1024] {
+        let sorted = sort_iter_by_key(values.iter().map(|v| v.to_string()), cap, |v| v.clone())
+            .unwrap()
+            .collect::<Result<Vec<String>, Error>>()
+            .unwrap();
+
+        assert_eq!(vec!["a\nb", "b", "c", "d", "e"], sorted);
+    }
+
+    let sorted = Sorter::new()
+        .capacity(8)
+        .reverse(true)
+        .unique(true)
+        .sort_iter_by_key(
+            ["a1", "b1", "a2", "c1", "b2"].iter().map(|v| v.to_string()),
+            |v| v[..1].to_string(),
+        )
+        .unwrap()
+        .collect::<Result<Vec<String>, Error>>()
+        .unwrap();
+
+    assert_eq!(vec!["c1", "b1", "a1"], sorted);
+}
+
+struct U32Codec;
+
+impl Codec<u32> for U32Codec {
+    fn encode(&self, value: &u32, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn decode<R: io::BufRead>(&self, reader: &mut R) -> io::Result<Option<u32>> {
+        let mut bytes = [0; 4];
+
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => Ok(Some(u32::from_le_bytes(bytes))),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[test]
+fn test_sort_iter_with_codec() {
+    let values = (0..1000u32).map(|i| i * 7919 % 1000);
+
+    let sorted = sort_iter_by_key_with(values, 64, U32Codec, |v| v % 10)
+        .unwrap()
+        .collect::<Result<Vec<u32>, Error>>()
+        .unwrap();
+
+    assert_eq!(1000, sorted.len());
+    assert!(sorted.windows(2).all(|w| w[0] % 10 <= w[1] % 10));
+
+    // Values with equal keys keep their input order.
+    let expected = (0..1000u32)
+        .map(|i| i * 7919 % 1000)
+        .filter(|v| v % 10 == 3)
+        .collect::<Vec<u32>>();
+    let actual = sorted
+        .iter()
+        .copied()
+        .filter(|v| v % 10 == 3)
+        .collect::<Vec<u32>>();
+
+    assert_eq!(expected, actual);
+
+    // 250 runs merged in several passes.
+    for fan_in in [2, 3, 64] {
+        let merged = Sorter::new()
+            .capacity(16)
+            .max_fan_in(fan_in)
+            .sort_iter_by_key_with((0..1000u32).map(|i| i * 7919 % 1000), U32Codec, |v| v % 10)
+            .unwrap()
+            .collect::<Result<Vec<u32>, Error>>()
+            .unwrap();
+
+        assert_eq!(sorted, merged);
+    }
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_sort_mmap() {
+    let third_col = |line: &String| line.split(',').nth(2).unwrap_or("").to_string();
+
+    for (input, unique) in [(CSV, false), (CSV.trim_end(), false), (CSV, true)] {
+        for cap in [1, 10, 100, 1024] {
+            let sort = |mmap: bool| {
+                let mut fin = tempfile::tempfile().unwrap();
+                write!(fin, "{}", input).unwrap();
+                fin.seek(io::SeekFrom::Start(0)).unwrap();
+                let mut buf = Vec::new();
+
+                Sorter::new()
+                    .capacity(cap)
+                    .stable(false)
+                    .unique(unique)
+                    .mmap(mmap)
+                    .sort_by_key(fin, &mut buf, third_col)
+                    .unwrap();
+
+                String::from_utf8(buf).unwrap()
+            };
+
+            assert_eq!(sort(false), sort(true));
+        }
+    }
+
+    // Runs are written from the map without splitting the input first, so each line is
+    // spilled once to its run and once by the merge.
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+
+    let stats = Sorter::new()
+        .capacity(100)
+        .mmap(true)
+        .sort_by_key(fin, io::sink(), |line| line.clone())
+        .unwrap();
+
+    assert_eq!(26, stats.records);
+    assert_eq!(4, stats.runs);
+    assert!(stats.peak_buffered_bytes <= 100);
+    assert_eq!(2 * CSV.len() as u64, stats.bytes_spilled);
+
+    let mut fin = tempfile::tempfile().unwrap();
+    fin.write_all(b"b\nc\n\xff\na\n").unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+
+    let err = Sorter::new()
+        .mmap(true)
+        .sort_by_key(fin, io::sink(), |line| line.clone())
+        .unwrap_err();
+
+    assert!(matches!(
+        err,
+        Error::InvalidEncoding {
+            line_num: 3,
+            offset: 4
+        }
+    ));
+}
+
+#[test]
+fn test_argsort() {
+    let third_col = |line: &String| line.split(',').nth(2).unwrap().to_string();
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut expected = Vec::new();
+
+    sort_by_key(fin, &mut expected, 1024, third_col).unwrap();
+
+    let lines = CSV.lines().collect::<Vec<&str>>();
+
+    for cap in [10, 1024] {
+        let mut buf = Vec::new();
+        let stats = Sorter::new()
+            .capacity(cap)
+            .max_fan_in(4)
+            .argsort_by_key(CSV.as_bytes(), &mut buf, Position::LineNumber, third_col)
+            .unwrap();
+
+        assert_eq!(26, stats.records);
+
+        if cap == 10 {
+            // The positions are spilled and merged in several passes.
+            assert!(stats.runs > 4);
+            assert!(stats.merges > 1);
+            assert!(stats.merge_passes > 1);
+            assert!(stats.bytes_spilled > 0);
+        } else {
+            assert_eq!(1, stats.runs);
+            assert_eq!(0, stats.merges);
+            assert_eq!(0, stats.bytes_spilled);
+        }
+
+        let reordered = str::from_utf8(&buf)
+            .unwrap()
+            .lines()
+            .map(|n| format!("{}\n", lines[n.parse::<usize>().unwrap() - 1]))
+            .collect::<String>();
+
+        assert_eq!(str::from_utf8(&expected).unwrap(), reordered);
+    }
+
+    let mut buf = Vec::new();
+    let stats = Sorter::new()
+        .reverse(true)
+        .argsort_by_key(
+            "bb\na\nccc\n".as_bytes(),
+            &mut buf,
+            Position::Offset,
+            |line| line.clone(),
+        )
+        .unwrap();
+
+    assert_eq!("5\n0\n3\n", str::from_utf8(&buf).unwrap());
+    assert_eq!(3, stats.records);
+}
+
+#[test]
+fn test_sstable() {
+    let key = |line: &String| line.split(',').next().unwrap().parse::<u32>().unwrap();
+    let input = (0..1000)
+        .map(|i| format!("{},{}\n", i * 7919 % 500, i))
+        .collect::<String>();
+
+    for opts in [
+        SsTableOptions::new().block_size(64),
+        SsTableOptions::new().bloom_filter(None),
+    ] {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "{}", input).unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut table = tempfile::tempfile().unwrap();
+
+        Sorter::new()
+            .capacity(1024)
+            .reverse(true)
+            .sort_by_key_to_sstable(fin, &mut table, opts, key)
+            .unwrap();
+
+        let table = SsTable::open(table, key).unwrap();
+
+        for k in [0, 1, 250, 499] {
+            let lines = table.get(&k).unwrap();
+            assert_eq!(2, lines.len());
+            assert!(lines.iter().all(|l| key(l) == k));
+        }
+
+        assert!(table.get(&500).unwrap().is_empty());
+
+        let lines = table
+            .range(10..20)
+            .collect::<Result<Vec<String>, Error>>()
+            .unwrap();
+        let keys = lines.iter().map(key).collect::<Vec<u32>>();
+        let expected = (10..20).flat_map(|k| [k, k]).collect::<Vec<u32>>();
+        assert_eq!(expected, keys);
+
+        assert_eq!(1000, table.range(..).count());
+        assert_eq!(2, table.range(499..).count());
+        assert_eq!(0, table.range(600..).count());
+    }
+
+    let mut bloom_misses = 0;
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", input).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut table = tempfile::tempfile().unwrap();
+
+    Sorter::new()
+        .sort_by_key_to_sstable(fin, &mut table, SsTableOptions::new(), key)
+        .unwrap();
+
+    let table = SsTable::open(table, key).unwrap();
+
+    for k in 500..1500 {
+        if table.may_contain(&k) {
+            bloom_misses += 1;
+        }
+    }
+
+    assert!(bloom_misses < 50, "{} false positives", bloom_misses);
+
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", input).unwrap();
+    assert!(matches!(SsTable::open(fin, key), Err(Error::Read(_))));
+
+    // Corrupted footers: a bloom filter without bits, and an index past the bloom filter.
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", input).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let mut table = Vec::new();
+
+    Sorter::new()
+        .sort_by_key_to_sstable(fin, &mut table, SsTableOptions::new(), key)
+        .unwrap();
+
+    for (field, value) in [(3, 4), (1, u64::MAX)] {
+        let mut bytes = table.clone();
+        let at = bytes.len() - 41 + field * 8;
+        bytes[at..at + 8].copy_from_slice(&u64::to_le_bytes(value));
+
+        let mut corrupted = tempfile::tempfile().unwrap();
+        corrupted.write_all(&bytes).unwrap();
+        assert!(matches!(SsTable::open(corrupted, key), Err(Error::Read(_))));
+    }
+
+    // Corrupted index: a block past the index, and a block length that overflows.
+    let footer = table.len() - 41;
+    let mut index = [0; 8];
+    index.copy_from_slice(&table[footer..footer + 8]);
+    let index = u64::from_le_bytes(index) as usize;
+
+    for (field, value) in [(0, index as u64), (1, u64::MAX)] {
+        let mut bytes = table.clone();
+        let at = index + field * 8;
+        bytes[at..at + 8].copy_from_slice(&u64::to_le_bytes(value));
+
+        let mut corrupted = tempfile::tempfile().unwrap();
+        corrupted.write_all(&bytes).unwrap();
+        assert!(matches!(SsTable::open(corrupted, key), Err(Error::Read(_))));
+    }
+}
+
+#[test]
+fn test_sorted_file() {
+    let key = |line: &String| line.split(',').next().unwrap().parse::<u32>().unwrap();
+    let mut sorted = tempfile::tempfile().unwrap();
+
+    for i in 0..1000 {
+        // Keys 0, 0, 2, 2, ..., 998, 998 with varying line lengths.
+        writeln!(sorted, "{},{}", i - i % 2, "x".repeat(i % 17)).unwrap();
+    }
+
+    let file = SortedFile::open(sorted, key).unwrap();
+
+    assert_eq!(Some(0), file.lower_bound(&0).unwrap().as_ref().map(key));
+    assert_eq!(Some(500), file.lower_bound(&499).unwrap().as_ref().map(key));
+    assert_eq!(Some(998), file.lower_bound(&998).unwrap().as_ref().map(key));
+    assert_eq!(None, file.lower_bound(&999).unwrap());
+
+    let keys = file
+        .range(10..=20)
+        .map(|l| key(&l.unwrap()))
+        .collect::<Vec<u32>>();
+    let expected = (10..=20)
+        .filter(|k| k % 2 == 0)
+        .flat_map(|k| [k, k])
+        .collect::<Vec<u32>>();
+    assert_eq!(expected, keys);
+
+    assert_eq!(1000, file.range(..).count());
+    assert_eq!(
+        4,
+        file.range((Bound::Excluded(994), Bound::Unbounded)).count()
+    );
+    assert_eq!(2, file.range(..1).count());
+    assert_eq!(0, file.range(999..).count());
+
+    // Ranges and lookups read from their own positions, past the buffer of each range.
+    let mut sorted = tempfile::tempfile().unwrap();
+
+    for i in 0..20000 {
+        writeln!(sorted, "{},{}", i, "x".repeat(i % 17)).unwrap();
+    }
+
+    let file = SortedFile::open(sorted, key).unwrap();
+    let mut low = file.range(..);
+    let mut high = file.range(10000..);
+    let mut keys = vec![];
+
+    for _ in 0..3000 {
+        keys.push(key(&low.next().unwrap().unwrap()));
+        keys.push(key(&high.next().unwrap().unwrap()));
+        assert_eq!(
+            Some(5000),
+            file.lower_bound(&5000).unwrap().as_ref().map(key)
+        );
+    }
+
+    let expected = (0..3000).flat_map(|i| [i, 10000 + i]).collect::<Vec<u32>>();
+    assert_eq!(expected, keys);
+
+    let mut sorted = tempfile::tempfile().unwrap();
+    write!(sorted, "c\0b\0b\0a").unwrap();
+    let file = SortedFile::open(sorted, |l: &String| l.trim_end_matches('\0').to_string())
+        .unwrap()
+        .delimiter(b'\0')
+        .reverse(true);
+    let lines = file
+        .range("b".to_string()..)
+        .collect::<Result<Vec<String>, Error>>()
+        .unwrap();
+    assert_eq!(vec!["b\0", "b\0", "a\0"], lines);
+}
+
+fn join(kind: JoinKind, left: &str, right: &str, cap: u64) -> Vec<(u32, Vec<String>, Vec<String>)> {
+    let key = |line: &String| line.split(',').next().unwrap().parse::<u32>().unwrap();
+    let mut fin1 = tempfile::tempfile().unwrap();
+    let mut fin2 = tempfile::tempfile().unwrap();
+    write!(fin1, "{}", left).unwrap();
+    write!(fin2, "{}", right).unwrap();
+    fin1.seek(io::SeekFrom::Start(0)).unwrap();
+    fin2.seek(io::SeekFrom::Start(0)).unwrap();
+
+    Sorter::new()
+        .capacity(cap)
+        .sort_and_join_by_key(fin1, fin2, kind, key)
+        .unwrap()
+        .map(|g| {
+            let g = g.unwrap();
+            let left = g
+                .left
+                .iter()
+                .collect::<Result<Vec<String>, Error>>()
+                .unwrap();
+            let right = g
+                .right
+                .iter()
+                .collect::<Result<Vec<String>, Error>>()
+                .unwrap();
+            assert_eq!((left.len(), right.len()), (g.left.len(), g.right.len()));
+            (g.key, left, right)
+        })
+        .collect()
+}
+
+#[test]
+fn test_join() {
+    let left = "3,c\n1,a\n2,b1\n2,b2\n";
+    let right = "4,D\n2,B\n3,C1\n3,C2\n";
+    let keys = |groups: Vec<(u32, Vec<String>, Vec<String>)>| {
+        groups
+            .iter()
+            .map(|(k, l, r)| (*k, l.len(), r.len()))
+            .collect::<Vec<(u32, usize, usize)>>()
+    };
+
+    assert_eq!(
+        vec![(2, 2, 1), (3, 1, 2)],
+        keys(join(JoinKind::Inner, left, right, 1024))
+    );
+    assert_eq!(
+        vec![(1, 1, 0), (2, 2, 1), (3, 1, 2)],
+        keys(join(JoinKind::Left, left, right, 1024))
+    );
+    assert_eq!(
+        vec![(2, 2, 1), (3, 1, 2), (4, 0, 1)],
+        keys(join(JoinKind::Right, left, right, 1024))
+    );
+    assert_eq!(
+        vec![(1, 1, 0), (2, 2, 1), (3, 1, 2), (4, 0, 1)],
+        keys(join(JoinKind::Full, left, right, 1024))
+    );
+
+    let groups = join(JoinKind::Inner, left, right, 1024);
+    assert_eq!(vec!["2,b1\n", "2,b2\n"], groups[0].1);
+    assert_eq!(vec!["3,C1\n", "3,C2\n"], groups[1].2);
+
+    // Groups larger than the capacity are spilled and read back in order.
+    let left = (0..100)
+        .map(|i| format!("{},{}\n", i % 2, i))
+        .collect::<String>();
+    let groups = join(JoinKind::Full, &left, "1,x\n", 16);
+    assert_eq!(2, groups.len());
+    let expected = (0..100)
+        .filter(|i| i % 2 == 1)
+        .map(|i| format!("1,{}\n", i))
+        .collect::<Vec<String>>();
+    assert_eq!(expected, groups[1].1);
+    assert_eq!(vec!["1,x\n"], groups[1].2);
+
+    assert!(join(JoinKind::Full, "", "", 1024).is_empty());
+}
+
+#[test]
+fn test_join_not_sorted() {
+    let key = |line: &String| line.trim_end().to_string();
+
+    let mut join = Sorter::new()
+        .verify(true)
+        .join_by_key("b\na\n".as_bytes(), "a\n".as_bytes(), JoinKind::Full, key)
+        .unwrap();
+
+    assert_eq!("a", join.next().unwrap().unwrap().key);
+    assert!(matches!(
+        join.next(),
+        Some(Err(Error::NotSorted {
+            input: 0,
+            line_num: 2
+        }))
+    ));
+}
+
+#[test]
+fn test_set_op() {
+    let left = "a,1\na,2\na,3\nb,1\nc,1\n";
+    let right = "a,4\nb,2\nb,3\nd,1\n";
+
+    let set_op = |op: SetOp, unique: bool| {
+        let mut buf = Vec::new();
+
+        let stats = Sorter::new()
+            .unique(unique)
+            .set_op_by_key(left.as_bytes(), right.as_bytes(), &mut buf, op, |l| {
+                l.split(',').next().unwrap().to_string()
+            })
+            .unwrap();
+
+        let out = String::from_utf8(buf).unwrap();
+        assert_eq!(out.lines().count() as u64, stats.records);
+        out
+    };
+
+    assert_eq!(
+        "a,1\na,2\na,3\nb,1\nb,3\nc,1\nd,1\n",
+        set_op(SetOp::Union, false)
+    );
+    assert_eq!("a,1\nb,1\n", set_op(SetOp::Intersection, false));
+    assert_eq!("a,2\na,3\nc,1\n", set_op(SetOp::Difference, false));
+    assert_eq!(
+        "a,2\na,3\nb,3\nc,1\nd,1\n",
+        set_op(SetOp::SymmetricDifference, false)
+    );
+
+    assert_eq!("a,1\nb,1\nc,1\nd,1\n", set_op(SetOp::Union, true));
+    assert_eq!("a,1\nb,1\n", set_op(SetOp::Intersection, true));
+    assert_eq!("c,1\n", set_op(SetOp::Difference, true));
+    assert_eq!("c,1\nd,1\n", set_op(SetOp::SymmetricDifference, true));
+}
+
+#[test]
+fn test_sort_by_key_grouped() {
+    let input = (0..100)
+        .map(|i| format!("{},{}\n", i % 3, i))
+        .collect::<String>();
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", input).unwrap();
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+
+    let sorter = Sorter::new().capacity(64);
+    let mut groups = sorter
+        .sort_by_key_grouped(fin, |l| l.split(',').next().unwrap().to_string())
+        .unwrap();
+
+    assert_eq!(100, groups.stats().records);
+
+    let group = groups.next_group().unwrap().unwrap();
+    assert_eq!("0", group.key());
+    let lines = group.collect::<Result<Vec<String>, Error>>().unwrap();
+    let expected = (0..100)
+        .filter(|i| i % 3 == 0)
+        .map(|i| format!("0,{}\n", i))
+        .collect::<Vec<String>>();
+    assert_eq!(expected, lines);
+
+    // Lines of a group that are not read are skipped.
+    let mut group = groups.next_group().unwrap().unwrap();
+    assert_eq!("1", group.key());
+    assert_eq!("1,1\n", group.next().unwrap().unwrap());
+
+    let group = groups.next_group().unwrap().unwrap();
+    assert_eq!("2", group.key());
+    assert_eq!(33, group.count());
+
+    assert!(groups.next_group().unwrap().is_none());
+    drop(groups);
+
+    // Runs are merged as the groups are read, and removed with the groups.
+    let tmp = tempfile::tempdir().unwrap();
+    let work = tempfile::tempdir().unwrap();
+    let files = |dir: &tempfile::TempDir| std::fs::read_dir(dir.path()).unwrap().count();
+
+    for work_dir in [false, true] {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "{}", input).unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+
+        let mut sorter = Sorter::new()
+            .capacity(64)
+            .max_fan_in(4)
+            .temp_dir(tmp.path());
+
+        if work_dir {
+            sorter = sorter.work_dir(work.path());
+        }
+
+        let mut groups = sorter
+            .sort_by_key_grouped(fin, |l| l.split(',').next().unwrap().to_string())
+            .unwrap();
+
+        assert!(groups.stats().merge_passes > 1);
+        assert!(files(if work_dir { &work } else { &tmp }) > 0);
+
+        let mut counts = vec![];
+
+        while let Some(group) = groups.next_group().unwrap() {
+            counts.push(group.count());
+        }
+
+        assert_eq!(vec![34, 33, 33], counts);
+        assert_eq!(0, files(&work));
+        drop(groups);
+        assert_eq!(0, files(&tmp));
+    }
+
+    let sorter = Sorter::new();
+    let mut groups = sorter
+        .sort_by_key_grouped(tempfile::tempfile().unwrap(), |l| l.clone())
+        .unwrap();
+    assert!(groups.next_group().unwrap().is_none());
+}
+
+#[test]
+fn test_sort_replacement_selection() {
+    let input = (0..500)
+        .map(|i| format!("{},{}\n", i * 7919 % 101, i))
+        .collect::<String>();
+    let key = |line: &String| line.split(',').next().unwrap().parse::<u32>().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let sort = |sorter: Sorter, input: &str| {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "{}", input).unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        let stats = sorter.sort_by_key(fin, &mut buf, key).unwrap();
+        (String::from_utf8(buf).unwrap(), stats)
+    };
+
+    for sorter in [
+        Sorter::new().capacity(200),
+        Sorter::new().capacity(200).reverse(true),
+        Sorter::new().capacity(200).unique(true),
+        Sorter::new().capacity(200).stable(false),
+        Sorter::new().capacity(200).work_dir(dir.path()),
+    ] {
+        let (expected, split) = sort(sorter.clone(), &input);
+        let (sorted, replaced) = sort(sorter.replacement_selection(true), &input);
+
+        assert_eq!(expected, sorted);
+        assert_eq!(split.records, replaced.records);
+        assert!(replaced.runs < split.runs);
+        assert!(replaced.peak_buffered_bytes <= 200 + 16);
+    }
+
+    assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+
+    let (sorted, _) = sort(Sorter::new().capacity(200), &input);
+    let (_, stats) = sort(
+        Sorter::new().capacity(200).replacement_selection(true),
+        &sorted,
+    );
+    assert_eq!(1, stats.runs);
+    assert_eq!(0, stats.merges);
+}
+
+#[test]
+fn test_sort_natural_runs() {
+    let line = |i: u32| format!("{},{}\n", i, i % 7);
+    let random = (0..500).map(|i| line(i * 7919 % 101)).collect::<String>();
+    let sorted = (0..500).map(line).collect::<String>();
+    let reversed = (0..500).rev().map(line).collect::<String>();
+    // Sorted except for one line near the start.
+    let nearly = (0..500)
+        .map(|i| line(if i == 20 { 400 } else { i }))
+        .collect::<String>();
+    let key = |line: &String| line.split(',').next().unwrap().parse::<u32>().unwrap();
+    let dir = tempfile::tempdir().unwrap();
+
+    let sort = |sorter: Sorter, input: &str| {
+        let mut fin = tempfile::tempfile().unwrap();
+        write!(fin, "{}", input).unwrap();
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let mut buf = Vec::new();
+        let stats = sorter.sort_by_key(fin, &mut buf, key).unwrap();
+        (String::from_utf8(buf).unwrap(), stats)
+    };
+
+    for sorter in [
+        Sorter::new().capacity(200),
+        Sorter::new().capacity(200).reverse(true),
+        Sorter::new().capacity(200).unique(true),
+        Sorter::new().capacity(200).stable(false),
+        Sorter::new().capacity(200).work_dir(dir.path()),
+    ] {
+        for input in [&random, &sorted, &reversed, &nearly] {
+            let (expected, split) = sort(sorter.clone(), input);
+
+            for natural_runs in [NaturalRuns::Sorted, NaturalRuns::SortedOrReversed] {
+                let (output, stats) = sort(sorter.clone().natural_runs(natural_runs), input);
+
+                assert_eq!(expected, output);
+                assert_eq!(split.records, stats.records);
+            }
+        }
+    }
+
+    assert_eq!(0, std::fs::read_dir(dir.path()).unwrap().count());
+
+    let natural = Sorter::new()
+        .capacity(200)
+        .natural_runs(NaturalRuns::Sorted);
+
+    // Sorted input is copied as it is.
+    let (output, stats) = sort(natural.clone(), &sorted);
+    assert_eq!(sorted, output);
+    assert_eq!(1, stats.runs);
+    assert_eq!(0, stats.merges);
+    assert_eq!(0, stats.bytes_spilled);
+
+    // The stretch after the line out of place is a run of any length.
+    let (_, stats) = sort(natural.clone(), &nearly);
+    assert_eq!(2, stats.runs);
+    assert_eq!(1, stats.merges);
+
+    // Reversed stretches are runs of `capacity` bytes.
+    let (_, stats) = sort(
+        natural.natural_runs(NaturalRuns::SortedOrReversed),
+        &reversed,
+    );
+    assert!(stats.runs > 1);
+    assert!(stats.peak_buffered_bytes <= 200 + 16);
+}
+
+#[test]
+fn test_merge_plan() {
+    assert!(merge::plan(&[], 4).is_empty());
+    assert!(merge::plan(&[10], 4).is_empty());
+    assert_eq!(vec![vec![0, 1, 2]], merge::plan(&[10, 10, 10], 4));
+
+    // 6 runs with a fan-in of 4: merge 3 of them first so that the second merge is full.
+    assert_eq!(
+        vec![vec![3, 4, 5], vec![0, 1, 2, 6]],
+        merge::plan(&[10, 10, 10, 1, 1, 1], 4)
+    );
+
+    // Only adjacent runs are merged, the smallest first.
+    assert_eq!(
+        vec![vec![1, 2], vec![0, 4], vec![5, 3]],
+        merge::plan(&[5, 1, 1, 9], 2)
+    );
+}
+
+#[test]
+fn test_sort_max_fan_in() {
+    let dir = tempfile::tempdir().unwrap();
+    let work_dir = dir.path().join("work");
+    let mut fin = tempfile::tempfile().unwrap();
+    write!(fin, "{}", CSV).unwrap();
+    let mut expected = Vec::new();
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let full = Sorter::new()
+        .capacity(10)
+        .sort_by_key(fin.try_clone().unwrap(), &mut expected, |line| line.clone())
+        .unwrap();
+
+    assert_eq!(26, full.runs);
+    assert_eq!(1, full.merges);
+
+    for fan_in in [0, 2, 3, 25] {
+        let mut buf = Vec::new();
+
+        fin.seek(io::SeekFrom::Start(0)).unwrap();
+        let stats = Sorter::new()
+            .capacity(10)
+            .max_fan_in(fan_in)
+            .sort_by_key(fin.try_clone().unwrap(), &mut buf, |line| line.clone())
+            .unwrap();
+
+        assert_eq!(expected, buf);
+        assert_eq!(26, stats.runs);
+        assert!(stats.merge_passes > 1);
+    }
+
+    // Cancelled during the second merge pass, then resumed.
+    let token = CancelToken::new();
+    let token2 = token.clone();
+    let mut buf = Vec::new();
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let err = Sorter::new()
+        .capacity(10)
+        .max_fan_in(3)
+        .work_dir(&work_dir)
+        .cancel_token(token)
+        .progress(move |p| {
+            if p.merge_pass == 2 {
+                token2.cancel();
+            }
+        })
+        .sort_by_key(fin.try_clone().unwrap(), &mut buf, |line| line.clone())
+        .unwrap_err();
+
+    assert!(is_cancelled(&err));
+
+    fin.seek(io::SeekFrom::Start(0)).unwrap();
+    let resumed = Sorter::new()
+        .capacity(10)
+        .max_fan_in(3)
+        .work_dir(&work_dir)
+        .sort_by_key(fin, &mut buf, |line| line.clone())
+        .unwrap();
+
+    assert_eq!(expected, buf);
+    assert_eq!(0, resumed.runs);
+    assert!(resumed.merges < 12);
+    assert_eq!(0, std::fs::read_dir(&work_dir).unwrap().count());
+}
@@ -0,0 +1,118 @@
+use super::context::Context;
+use super::error::Result;
+use super::file_utils::Records;
+use super::order::Order;
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::io::BufRead;
+
+struct Entry<K> {
+    key: K,
+    seq: u64,
+    line: String,
+    order: Order,
+}
+
+impl<K: Ord> Ord for Entry<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order
+            .cmp(&self.key, &self.line, &other.key, &other.line)
+            .then_with(|| self.seq.cmp(&other.seq))
+    }
+}
+
+impl<K: Ord> PartialOrd for Entry<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord> PartialEq for Entry<K> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K: Ord> Eq for Entry<K> {}
+
+pub(crate) enum TopK {
+    // All lines were read and the `k` first lines fit in `cap`.
+    InMemory(Vec<String>),
+    // The `k` first lines did not fit in `cap`. The lines kept so far are returned
+    // so that the caller can sort them together with the rest of the reader.
+    Overflow(Vec<String>),
+}
+
+pub(crate) fn top_k<R, F, K>(
+    records: &mut Records<R>,
+    k: usize,
+    ctx: &Context,
+    key: &F,
+) -> Result<TopK>
+where
+    R: BufRead,
+    F: Fn(&String) -> K,
+    K: Ord,
+{
+    let order = ctx.sorter.order();
+    let mut set = BTreeSet::new();
+    let mut bytes = 0;
+    let mut peak = 0;
+    let mut seq = 0;
+    let mut buf = String::new();
+
+    if k == 0 {
+        return Ok(TopK::InMemory(vec![]));
+    }
+
+    while records.read(&mut buf)? > 0 {
+        ctx.check_cancelled()?;
+        let entry = Entry {
+            key: key(&buf),
+            seq,
+            line: std::mem::take(&mut buf),
+            order,
+        };
+
+        seq += 1;
+
+        if set.len() == k && set.last().is_some_and(|last| entry >= *last) {
+            continue;
+        }
+
+        if let Some(prev) = set.range(..&entry).next_back() {
+            if order.is_dup(&prev.key, &entry.key) {
+                continue;
+            }
+        }
+
+        bytes += entry.line.len() as u64;
+        set.insert(entry);
+
+        if set.len() > k {
+            if let Some(last) = set.pop_last() {
+                bytes -= last.line.len() as u64;
+            }
+        }
+
+        peak = peak.max(bytes);
+
+        if bytes > ctx.sorter.cap {
+            // The kept lines are counted again when the caller sorts them.
+            let kept = set.len() as u64;
+            ctx.stats(|s| {
+                s.records += seq - kept;
+                s.peak_buffered_bytes = peak;
+            });
+
+            return Ok(TopK::Overflow(set.into_iter().map(|e| e.line).collect()));
+        }
+    }
+
+    ctx.stats(|s| {
+        s.records += seq;
+        s.peak_buffered_bytes = peak;
+    });
+
+    Ok(TopK::InMemory(set.into_iter().map(|e| e.line).collect()))
+}
@@ -0,0 +1,94 @@
+use super::context::Context;
+use super::file_utils;
+use super::Error;
+use super::Result;
+use super::SortStats;
+use super::Sorter;
+use std::cell::RefCell;
+use std::error;
+use std::fs;
+use std::io;
+use std::io::Write;
+
+/// What `try_sort_by_key` does with a line for which the key function fails.
+pub enum OnKeyError<'a> {
+    /// Fail with `Error::Key`.
+    Abort,
+    /// Leave the line out of the output.
+    Skip,
+    /// Write the line to the given writer, in input order, instead of the output.
+    Reject(&'a mut dyn io::Write),
+}
+
+impl Sorter {
+    /// Same as `sort_by_key` with a key function that can fail.
+    ///
+    /// Lines are left out as runs are generated, and the key function is called again
+    /// for the lines that are sorted. With `work_dir`, a resumed sort reports only the
+    /// lines of the runs that it generates again.
+    pub fn try_sort_by_key<T, F, K, E>(
+        &self,
+        fin: fs::File,
+        fout: T,
+        on_error: OnKeyError,
+        key: F,
+    ) -> Result<SortStats>
+    where
+        T: io::Write,
+        F: Fn(&str) -> std::result::Result<K, E>,
+        E: Into<Box<dyn error::Error + Send + Sync>>,
+        K: Ord,
+    {
+        let mut on_error = RefCell::new(on_error);
+        let filter = |line: &str, line_num: u64, offset: u64| {
+            let e = match key(line) {
+                Ok(_) => return Ok(true),
+                Err(e) => e,
+            };
+
+            match &mut *on_error.borrow_mut() {
+                OnKeyError::Abort => Err(Error::Key {
+                    line_num,
+                    offset,
+                    source: e.into(),
+                }),
+                OnKeyError::Skip => Ok(false),
+                OnKeyError::Reject(w) => {
+                    w.write_all(line.as_bytes()).map_err(Error::write)?;
+                    Ok(false)
+                }
+            }
+        };
+
+        // Every line left has a key, so `None` is never compared.
+        let sort_key = |line: &String| key(line).ok();
+        let output = |sorted: &fs::File, ctx: &Context| {
+            file_utils::copy(sorted, ctx.writer(fout), self.delim)
+        };
+        let stats = self.sort_filtered_then(fin, &sort_key, None, Some(&filter), output)?;
+
+        if let OnKeyError::Reject(w) = on_error.get_mut() {
+            w.flush().map_err(Error::write)?;
+        }
+
+        Ok(stats)
+    }
+}
+
+pub fn try_sort_by_key<T, F, K, E>(
+    fin: fs::File,
+    fout: T,
+    cap: u64,
+    on_error: OnKeyError,
+    key: F,
+) -> Result<SortStats>
+where
+    T: io::Write,
+    F: Fn(&str) -> std::result::Result<K, E>,
+    E: Into<Box<dyn error::Error + Send + Sync>>,
+    K: Ord,
+{
+    Sorter::new()
+        .capacity(cap)
+        .try_sort_by_key(fin, fout, on_error, key)
+}
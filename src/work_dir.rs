@@ -0,0 +1,237 @@
+use super::file_utils;
+use super::sstable;
+use io::prelude::BufRead;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::UNIX_EPOCH;
+
+const MANIFEST: &str = "manifest";
+const HEADER: &str = "ex_merge_sort_by_key 2";
+pub(crate) const SAMPLES: &str = "samples";
+
+// Completed steps of a sort, keyed by chunk id.
+//
+// A chunk id is "r" for the input and the id of the parent followed by "0" or "1"
+// for the halves of a split. Merges are numbered in the order of the merge plan,
+// "m0", "m1" and so on. The manifest has one line per completed step:
+//
+//     split <id>                <id>0.in and <id>1.in hold the halves of the chunk
+//     run <id>                  <id>.run holds the chunk sorted in memory
+//     samples                   `SAMPLES` holds the lines sampled from all the runs
+//     runs <file>:<len> ...     all the runs to merge, in input order
+//     merge <id> <pass>         <id>.run holds a merge of the given pass
+//
+// Files that are not listed in the manifest may be incomplete and are rewritten.
+// Each file is removed once the step that reads it has completed, and `finish`
+// removes the files listed in the manifest, leaving other files in the directory.
+#[derive(Debug, PartialEq)]
+enum Step {
+    Split,
+    Sorted,
+}
+
+pub(crate) struct WorkDir {
+    dir: PathBuf,
+    manifest: RefCell<fs::File>,
+    steps: HashMap<String, Vec<Step>>,
+    runs: Option<Vec<(String, u64)>>,
+    samples: bool,
+    // Files written by the steps in the manifest.
+    files: RefCell<Vec<String>>,
+}
+
+impl WorkDir {
+    // `settings` must be the same as the sort being resumed.
+    pub(crate) fn open(dir: &Path, settings: &str) -> io::Result<WorkDir> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(MANIFEST);
+        let mut steps = HashMap::new();
+        let mut runs = None;
+        let mut samples = false;
+        let mut files = vec![];
+
+        if path.exists() {
+            let reader = io::BufReader::new(fs::File::open(&path)?);
+            let mut lines = reader.lines();
+            let header = lines.next().transpose()?;
+            let prev_settings = lines.next().transpose()?;
+
+            if header.as_deref() != Some(HEADER) || prev_settings.as_deref() != Some(settings) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{} belongs to a sort with different input or settings",
+                        dir.display()
+                    ),
+                ));
+            }
+
+            for line in lines {
+                let line = line?;
+                let cols = line.split(' ').collect::<Vec<&str>>();
+
+                let (id, step) = match cols.as_slice() {
+                    ["split", id] => (id, Step::Split),
+                    ["run", id] => (id, Step::Sorted),
+                    ["merge", id, pass] if pass.parse::<u64>().is_ok() => (id, Step::Sorted),
+                    ["samples"] => {
+                        files.extend(step_files(&cols));
+                        samples = true;
+                        continue;
+                    }
+                    ["runs", names @ ..] => match parse_runs(names) {
+                        Some(names) => {
+                            files.extend(step_files(&cols));
+                            runs = Some(names);
+                            continue;
+                        }
+                        None => break,
+                    },
+                    // A line cut by a crash.
+                    _ => break,
+                };
+
+                files.extend(step_files(&cols));
+                steps
+                    .entry(id.to_string())
+                    .or_insert_with(Vec::new)
+                    .push(step);
+            }
+        } else {
+            let mut f = fs::File::create(&path)?;
+            writeln!(f, "{}\n{}", HEADER, settings)?;
+            f.sync_all()?;
+        }
+
+        let manifest = fs::OpenOptions::new().append(true).open(&path)?;
+
+        Ok(WorkDir {
+            dir: dir.to_path_buf(),
+            manifest: RefCell::new(manifest),
+            steps,
+            runs,
+            samples,
+            files: RefCell::new(files),
+        })
+    }
+
+    pub(crate) fn create(&self, name: &str) -> io::Result<fs::File> {
+        file_utils::create(&self.dir.join(name))
+    }
+
+    pub(crate) fn open_file(&self, name: &str) -> io::Result<fs::File> {
+        file_utils::open(&self.dir.join(name))
+    }
+
+    // Make `files` durable, then record `step` in the manifest.
+    pub(crate) fn commit(&self, files: &[&fs::File], step: &str) -> io::Result<()> {
+        for f in files {
+            f.sync_all()?;
+        }
+
+        let mut manifest = self.manifest.borrow_mut();
+        writeln!(manifest, "{}", step)?;
+        manifest.sync_data()?;
+
+        let cols = step.split(' ').collect::<Vec<&str>>();
+        self.files.borrow_mut().extend(step_files(&cols));
+        Ok(())
+    }
+
+    // The chunk `id` sorted in memory, or the output of the merge `id`.
+    pub(crate) fn sorted(&self, id: &str) -> io::Result<Option<fs::File>> {
+        if !self.is_sorted(id) {
+            return Ok(None);
+        }
+
+        Ok(Some(self.open_file(&format!("{}.run", id))?))
+    }
+
+    // Same as `sorted` without opening the file, which is removed once it is merged.
+    pub(crate) fn is_sorted(&self, id: &str) -> bool {
+        self.steps
+            .get(id)
+            .is_some_and(|s| s.contains(&Step::Sorted))
+    }
+
+    pub(crate) fn is_split(&self, id: &str) -> bool {
+        self.steps.get(id).is_some_and(|s| s.contains(&Step::Split))
+    }
+
+    // The files and lengths of the runs to merge, once they have all been generated.
+    pub(crate) fn runs(&self) -> Option<Vec<(String, u64)>> {
+        self.runs.clone()
+    }
+
+    pub(crate) fn has_samples(&self) -> bool {
+        self.samples
+    }
+
+    pub(crate) fn remove(&self, name: &str) -> io::Result<()> {
+        file_utils::remove(&self.dir.join(name))
+    }
+
+    // Remove the manifest and the files of its steps after the sort has completed.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        for name in self.files.borrow().iter() {
+            self.remove(name)?;
+        }
+
+        self.remove(MANIFEST)
+    }
+}
+
+fn parse_runs(files: &[&str]) -> Option<Vec<(String, u64)>> {
+    files
+        .iter()
+        .map(|f| {
+            let (name, len) = f.rsplit_once(':')?;
+            Some((name.to_string(), len.parse().ok()?))
+        })
+        .collect()
+}
+
+// Files written by a step of the manifest, split in columns.
+fn step_files(cols: &[&str]) -> Vec<String> {
+    match cols {
+        ["split", id] => vec![format!("{}0.in", id), format!("{}1.in", id)],
+        ["run", id] | ["merge", id, _] => vec![format!("{}.run", id)],
+        ["samples"] => vec![SAMPLES.to_string()],
+        ["runs", names @ ..] => parse_runs(names)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect(),
+        _ => vec![],
+    }
+}
+
+// Identifies the input of a sort by its length, modification time and a hash of its
+// first and last blocks, so that a different input is not resumed with stale runs.
+pub(crate) fn fingerprint(mut f: &fs::File) -> io::Result<String> {
+    const BLOCK: u64 = 4096;
+
+    let meta = f.metadata()?;
+    let len = meta.len();
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_nanos());
+    let mut bytes = vec![];
+
+    for offset in [0, len.saturating_sub(BLOCK)] {
+        f.seek(io::SeekFrom::Start(offset))?;
+        f.take(BLOCK).read_to_end(&mut bytes)?;
+    }
+
+    f.seek(io::SeekFrom::Start(0))?;
+    Ok(format!("{}:{}:{:016x}", len, mtime, sstable::hash(&bytes)))
+}